@@ -0,0 +1,108 @@
+//! How long `sendme send` takes to import a tree of many small files, and how
+//! much memory it needs for that.
+//!
+//! `cargo bench --bench import -- 100000 1000000` imports trees with that many
+//! files, 100000 by default. Each tree is imported twice, the second time with
+//! the hashes from the cache. The time to the first progress report shows when
+//! hashing started, the time to the ticket when the share was ready.
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Files per directory of the synthetic tree.
+const FILES_PER_DIR: usize = 1000;
+
+fn main() {
+    let counts = std::env::args()
+        .skip(1)
+        // cargo passes --bench
+        .filter(|arg| !arg.starts_with('-'))
+        .map(|arg| arg.parse().expect("arguments are file counts"))
+        .collect::<Vec<usize>>();
+    let counts = if counts.is_empty() {
+        vec![100_000]
+    } else {
+        counts
+    };
+    for count in counts {
+        let tree = tempfile::tempdir().unwrap();
+        let data_dir = tempfile::tempdir().unwrap();
+        let started = Instant::now();
+        make_tree(tree.path(), count);
+        println!("{count} files, created in {:.2?}", started.elapsed());
+        for run in ["hashed", "cached"] {
+            let result = import(tree.path(), data_dir.path());
+            println!(
+                "  {run}: first progress after {:.2?}, ticket after {:.2?}, peak memory {}",
+                result.first_progress,
+                result.ticket,
+                result.peak_memory.map_or_else(
+                    || "unknown".to_string(),
+                    |kib| format!("{} MiB", kib / 1024)
+                )
+            );
+        }
+    }
+}
+
+/// Directories of small files, each with different content.
+fn make_tree(root: &Path, count: usize) {
+    for i in 0..count {
+        let dir = root.join(format!("d{}", i / FILES_PER_DIR));
+        if i % FILES_PER_DIR == 0 {
+            std::fs::create_dir(&dir).unwrap();
+        }
+        std::fs::write(dir.join(format!("f{i}")), i.to_le_bytes()).unwrap();
+    }
+}
+
+struct Import {
+    first_progress: Duration,
+    ticket: Duration,
+    /// Peak resident memory of the sender in KiB, where the system tells.
+    peak_memory: Option<u64>,
+}
+
+fn import(tree: &Path, data_dir: &Path) -> Import {
+    let started = Instant::now();
+    let mut sender = Command::new(env!("CARGO_BIN_EXE_sendme"))
+        .arg("send")
+        .arg(tree)
+        .arg("--json")
+        .arg("--data-dir")
+        .arg(data_dir)
+        .env_remove("RUST_LOG") // disable tracing
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut first_progress = None;
+    let mut lines = BufReader::new(sender.stdout.take().unwrap()).lines();
+    let ticket = loop {
+        let line = lines.next().expect("send exited without a ticket").unwrap();
+        let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+        match event["event"].as_str() {
+            Some("import_progress") => {
+                first_progress.get_or_insert_with(|| started.elapsed());
+            }
+            Some("ticket_issued") => break started.elapsed(),
+            _ => {}
+        }
+    };
+    let peak_memory = peak_memory(sender.id());
+    sender.kill().unwrap();
+    sender.wait().unwrap();
+    Import {
+        first_progress: first_progress.unwrap_or(ticket),
+        ticket,
+        peak_memory,
+    }
+}
+
+/// The high water mark of the resident memory of a process, on linux.
+fn peak_memory(pid: u32) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
@@ -1,40 +1,26 @@
-pub mod send;
+pub mod cache;
+pub mod collection;
+pub mod identity;
+pub mod metadata;
 pub mod receive;
+pub mod report;
+pub mod scratch;
+pub mod send;
+pub mod share;
+pub mod upload;
 
-use crate::interconnect::{ AddrInfoOptions, Format};
+use crate::interconnect::{AddrInfoOptions, CommonArgs, Format};
 use anyhow::Context;
 use futures_buffered::BufferedStreamExt;
-use iroh::{
-    NodeAddr, SecretKey,
-};
-use iroh_blobs::{
-    provider::{CustomEventSender},
-     Hash,
-};
-use n0_future::{ StreamExt};
-use rand::{Rng};
+use indicatif::HumanBytes;
+use iroh::NodeAddr;
+use iroh_blobs::Hash;
+use n0_future::StreamExt;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::fmt::Display;
 use std::ops::Deref;
-use std::{
-    fmt::{Display},
-    str::FromStr,
-};
-
-/// Get the secret key or generate a new one.
-///
-/// Print the secret key to stderr if it was generated, so the user can save it.
-fn get_or_create_secret(print: bool) -> anyhow::Result<SecretKey> {
-    match std::env::var("IROH_SECRET") {
-        Ok(secret) => SecretKey::from_str(&secret).context("invalid secret"),
-        Err(_) => {
-            let key = SecretKey::generate(rand::rngs::OsRng);
-            if print {
-                eprintln!("using secret key {}", key);
-            }
-            Ok(key)
-        }
-    }
-}
+use std::path::{Path, PathBuf};
 
 pub fn apply_options(addr: &mut NodeAddr, opts: AddrInfoOptions) {
     match opts {
@@ -54,6 +40,47 @@ pub fn apply_options(addr: &mut NodeAddr, opts: AddrInfoOptions) {
     }
 }
 
+/// The directory sendme keeps its data in, like the shares that outlive a restart.
+///
+/// That is `custom` if given, else `SENDME_DATA_DIR`, else the platform data directory.
+pub fn data_dir(custom: Option<&Path>) -> anyhow::Result<PathBuf> {
+    if let Some(dir) = custom {
+        return Ok(dir.to_owned());
+    }
+    if let Some(dir) = std::env::var_os("SENDME_DATA_DIR") {
+        return Ok(dir.into());
+    }
+    let dir = dirs::data_local_dir()
+        .context("no data directory on this platform, choose one with --data-dir")?;
+    Ok(dir.join("sendme"))
+}
+
+/// A directory below the data directory, created if needed.
+pub(crate) async fn create_data_dir(common: &CommonArgs, path: &Path) -> anyhow::Result<PathBuf> {
+    let root = data_dir(common.data_dir.as_deref())?;
+    let dir = root.join(path);
+    tokio::fs::create_dir_all(&dir).await.with_context(|| {
+        format!(
+            "can not write to {}, choose another data directory with --data-dir or SENDME_DATA_DIR",
+            root.display()
+        )
+    })?;
+    Ok(dir)
+}
+
+/// Fail if the file system of `path` has less than `needed` bytes free.
+pub(crate) fn ensure_space(path: &Path, needed: u64) -> anyhow::Result<()> {
+    let available = fs2::available_space(path)
+        .with_context(|| format!("can not tell the free space in {}", path.display()))?;
+    anyhow::ensure!(
+        available >= needed,
+        "not enough space in {}, {} needed but only {} free",
+        path.display(),
+        HumanBytes(needed),
+        HumanBytes(available)
+    );
+    Ok(())
+}
 
 pub fn print_hash(hash: &Hash, format: Format) -> String {
     match format {
@@ -62,99 +89,6 @@ pub fn print_hash(hash: &Hash, format: Format) -> String {
     }
 }
 
-
-
-
-
-// #[derive(Debug, Clone)]
-// struct SendStatus {
-//     /// the multiprogress bar
-//     mp: MultiProgress,
-// }
-//
-// impl SendStatus {
-//     fn new() -> Self {
-//         let mp = MultiProgress::new();
-//         mp.set_draw_target(ProgressDrawTarget::stderr());
-//         Self { mp }
-//     }
-//
-//     fn new_client(&self) -> ClientStatus {
-//         let current = self.mp.add(ProgressBar::hidden());
-//         current.set_style(
-//             ProgressStyle::default_spinner()
-//                 .template("{spinner:.green} [{elapsed_precise}] {msg}")
-//                 .unwrap(),
-//         );
-//         current.enable_steady_tick(Duration::from_millis(100));
-//         current.set_message("waiting for requests");
-//         ClientStatus {
-//             current: current.into(),
-//         }
-//     }
-// }
-
-// #[derive(Debug, Clone)]
-// struct ClientStatus {
-//     current: Arc<ProgressBar>,
-// }
-//
-// impl Drop for ClientStatus {
-//     fn drop(&mut self) {
-//         if Arc::strong_count(&self.current) == 1 {
-//             self.current.finish_and_clear();
-//         }
-//     }
-// }
-//
-// impl CustomEventSender for ClientStatus {
-//     fn send(&self, event: iroh_blobs::provider::Event) -> Boxed<()> {
-//         self.try_send(event);
-//         Box::pin(std::future::ready(()))
-//     }
-//
-//     fn try_send(&self, event: provider::Event) {
-//         tracing::info!("{:?}", event);
-//         let msg = match event {
-//             provider::Event::ClientConnected { connection_id } => {
-//                 Some(format!("{} got connection", connection_id))
-//             }
-//             provider::Event::TransferBlobCompleted {
-//                 connection_id,
-//                 hash,
-//                 index,
-//                 size,
-//                 ..
-//             } => Some(format!(
-//                 "{} transfer blob completed {} {} {}",
-//                 connection_id,
-//                 hash,
-//                 index,
-//                 HumanBytes(size)
-//             )),
-//             provider::Event::TransferCompleted {
-//                 connection_id,
-//                 stats,
-//                 ..
-//             } => Some(format!(
-//                 "{} transfer completed {} {}",
-//                 connection_id,
-//                 stats.send.write_bytes.size,
-//                 HumanDuration(stats.send.write_bytes.stats.duration)
-//             )),
-//             provider::Event::TransferAborted { connection_id, .. } => {
-//                 Some(format!("{} transfer completed", connection_id))
-//             }
-//             _ => None,
-//         };
-//         if let Some(msg) = msg {
-//             self.current.set_message(msg);
-//         }
-//     }
-// }
-
-
-
 // fn show_get_error(e: anyhow::Error) -> anyhow::Error {
 //     if let Some(err) = e.downcast_ref::<DecodeError>() {
 //         match err {
@@ -200,4 +134,3 @@ pub fn print_hash(hash: &Hash, format: Format) -> String {
 //     }
 //     e
 // }
-
@@ -0,0 +1,217 @@
+//! Hashes of shared files, kept between runs so unchanged files are not hashed again.
+//!
+//! The cache is a store in `cache` of the data directory, whose blobs reference
+//! the files where they are, and an index that says which blob belongs to which
+//! file. A file counts as unchanged while its canonical path, size, modification
+//! time and inode stay the same. Only one transfer at a time uses the cache, it
+//! holds the lock file meanwhile.
+use crate::backend::scratch::{is_locked, usage};
+use crate::backend::{create_data_dir, data_dir};
+use crate::interconnect::CommonArgs;
+use anyhow::Context;
+use fs2::FileExt;
+use iroh_blobs::Hash;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, UNIX_EPOCH},
+};
+
+const CACHE: &str = "cache";
+const STORE_NAME: &str = "store";
+const LOCK_NAME: &str = "lock";
+const INDEX_NAME: &str = "index.json";
+
+/// A file as it was when it was hashed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CacheKey {
+    /// The canonical path of the file.
+    pub path: PathBuf,
+    pub size: u64,
+    /// Modification time since the unix epoch.
+    pub modified: Option<Duration>,
+    /// The inode on unix, so a file that was replaced by another one is noticed.
+    pub inode: Option<u64>,
+}
+
+impl CacheKey {
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        let path = std::fs::canonicalize(path)?;
+        let metadata = std::fs::metadata(&path)?;
+        #[cfg(unix)]
+        let inode = Some(std::os::unix::fs::MetadataExt::ino(&metadata));
+        #[cfg(not(unix))]
+        let inode = None;
+        Ok(Self {
+            path,
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()),
+            inode,
+        })
+    }
+
+    /// Whether the file is still the same.
+    fn is_unchanged(&self) -> bool {
+        Self::read(&self.path).is_ok_and(|current| current == *self)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    key: CacheKey,
+    hash: Hash,
+}
+
+/// The hash cache, locked until it is dropped.
+#[derive(Debug)]
+pub(crate) struct HashCache {
+    store: PathBuf,
+    _lock: File,
+    /// By canonical path.
+    entries: Mutex<BTreeMap<PathBuf, Entry>>,
+}
+
+impl HashCache {
+    /// Open the cache, or return `None` if another transfer uses it.
+    pub async fn open(common: &CommonArgs) -> anyhow::Result<Option<Self>> {
+        let store = create_data_dir(common, &Path::new(CACHE).join(STORE_NAME)).await?;
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(store.with_file_name(LOCK_NAME))?;
+        if lock.try_lock_exclusive().is_err() {
+            return Ok(None);
+        }
+        let entries = read_index(&store.with_file_name(INDEX_NAME))?
+            .into_iter()
+            .map(|entry| (entry.key.path.clone(), entry))
+            .collect();
+        Ok(Some(Self {
+            store,
+            _lock: lock,
+            entries: Mutex::new(entries),
+        }))
+    }
+
+    /// The directory of the store with the blobs of the cached files.
+    pub fn store_dir(&self) -> &Path {
+        &self.store
+    }
+
+    /// The blob of a file, if it was hashed as it is now.
+    pub fn get(&self, key: &CacheKey) -> Option<Hash> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&key.path)
+            .filter(|entry| entry.key == *key)
+            .map(|entry| entry.hash)
+    }
+
+    pub fn insert(&self, key: CacheKey, hash: Hash) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key.path.clone(), Entry { key, hash });
+    }
+
+    /// Forget the files of blobs that were deleted from the store.
+    pub fn forget(&self, hashes: &BTreeSet<Hash>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| !hashes.contains(&entry.hash));
+    }
+
+    /// The blobs of all cached files, which stay in the store.
+    pub fn hashes(&self) -> BTreeSet<Hash> {
+        let entries = self.entries.lock().unwrap();
+        entries.values().map(|entry| entry.hash).collect()
+    }
+
+    /// Forget the files that changed or are gone since they were hashed.
+    pub fn prune(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.key.is_unchanged());
+    }
+
+    /// Write the index.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let entries = self
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let index = self.store.with_file_name(INDEX_NAME);
+        let temp = index.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_vec(&entries)?)?;
+        std::fs::rename(temp, index)?;
+        Ok(())
+    }
+}
+
+/// What the hash cache holds.
+#[derive(Debug, Clone)]
+pub struct CacheInfo {
+    pub dir: PathBuf,
+    /// Files with a cached hash.
+    pub files: usize,
+    /// Total size of these files in bytes.
+    pub size: u64,
+    /// Files that changed since they were hashed, and will be hashed again.
+    pub changed: usize,
+    /// What the cache takes on disk in bytes, mostly outboards.
+    pub disk_usage: u64,
+    /// Whether a running transfer uses the cache.
+    pub in_use: bool,
+}
+
+/// Look at the hash cache, `custom` is the data directory like for [`data_dir`].
+pub fn info(custom: Option<&Path>) -> anyhow::Result<CacheInfo> {
+    let dir = data_dir(custom)?.join(CACHE);
+    let entries = read_index(&dir.join(INDEX_NAME))?;
+    let disk_usage = if dir.exists() { usage(&dir)?.0 } else { 0 };
+    Ok(CacheInfo {
+        files: entries.len(),
+        size: entries.iter().map(|entry| entry.key.size).sum(),
+        changed: entries
+            .iter()
+            .filter(|entry| !entry.key.is_unchanged())
+            .count(),
+        disk_usage,
+        in_use: dir.exists() && is_locked(&dir)?,
+        dir,
+    })
+}
+
+/// Delete the hash cache, unless a transfer is using it.
+pub fn clear(custom: Option<&Path>) -> anyhow::Result<()> {
+    let dir = data_dir(custom)?.join(CACHE);
+    if !dir.exists() {
+        return Ok(());
+    }
+    anyhow::ensure!(
+        !is_locked(&dir)?,
+        "{} is used by another transfer",
+        dir.display()
+    );
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+fn read_index(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).with_context(|| {
+            format!(
+                "invalid hash cache index {}, `sendme cache --clear` removes it",
+                path.display()
+            )
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
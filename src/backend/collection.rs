@@ -0,0 +1,252 @@
+//! Collections with more entries than should be kept in memory at once.
+//!
+//! The entries are sorted in runs of at most [`RUN_ENTRIES`], each of which is a
+//! blob in the database until the collection is stored. Storing merges the runs
+//! into the blobs [`Collection::store`] would write, a chunk at a time.
+use anyhow::Context;
+use bytes::Bytes;
+use iroh_blobs::{
+    format::collection::Collection,
+    store::{MapEntry, Store},
+    util::progress::IgnoreProgressSender,
+    BlobFormat, Hash, TempTag,
+};
+use iroh_io::AsyncSliceReader;
+use std::collections::BTreeMap;
+
+/// How many entries are sorted in memory before they go to the database.
+const RUN_ENTRIES: usize = 64 * 1024;
+
+/// How many bytes of a run are read, and of a collection blob written, at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Builds a collection sorted by name, whatever order the entries come in.
+#[derive(Debug)]
+pub(crate) struct CollectionBuilder<D> {
+    db: D,
+    run: BTreeMap<String, Hash>,
+    /// Keep the runs that are already written until the collection is stored.
+    runs: Vec<TempTag>,
+    len: u64,
+}
+
+impl<D: Store> CollectionBuilder<D> {
+    pub fn new(db: D) -> Self {
+        Self {
+            db,
+            run: BTreeMap::new(),
+            runs: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Add an entry. Names must be unique.
+    pub async fn push(&mut self, name: String, hash: Hash) -> anyhow::Result<()> {
+        self.run.insert(name, hash);
+        self.len += 1;
+        if self.run.len() >= RUN_ENTRIES {
+            self.write_run().await?;
+        }
+        Ok(())
+    }
+
+    /// Store the collection, with `last` after all other entries if given.
+    ///
+    /// The blobs of the entries must still be in the database. The runs are
+    /// deleted from it again.
+    pub async fn store(mut self, last: Option<(String, Hash)>) -> anyhow::Result<TempTag> {
+        self.write_run().await?;
+        let runs = self.runs.iter().map(|tag| *tag.hash()).collect::<Vec<_>>();
+        // the names go first, as postcard encodes them
+        let len = self.len + u64::from(last.is_some());
+        let mut header = Collection::HEADER.to_vec();
+        push_varint(&mut header, len);
+        let mut footer = Vec::new();
+        if let Some((name, _)) = &last {
+            push_name(&mut footer, name);
+        }
+        let names = self
+            .import_merged(&runs, header, footer, |chunk, name, _| {
+                push_name(chunk, name)
+            })
+            .await?;
+        // then the hash seq, the names blob and all blobs of the entries
+        let header = names.hash().as_bytes().to_vec();
+        let footer = last
+            .map(|(_, hash)| hash.as_bytes().to_vec())
+            .unwrap_or_default();
+        let collection = self
+            .import_merged(&runs, header, footer, |chunk, _, hash| {
+                chunk.extend_from_slice(hash.as_bytes())
+            })
+            .await?;
+        let collection = self
+            .db
+            .temp_tag(iroh_blobs::HashAndFormat::hash_seq(*collection.hash()));
+        drop(names);
+        self.runs.clear();
+        self.db.delete(runs).await?;
+        Ok(collection)
+    }
+
+    /// Write the entries in memory as a run: the length of the name as four
+    /// bytes, the name and the hash, for each entry.
+    async fn write_run(&mut self) -> anyhow::Result<()> {
+        if self.run.is_empty() {
+            return Ok(());
+        }
+        let mut data = Vec::new();
+        for (name, hash) in std::mem::take(&mut self.run) {
+            data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(hash.as_bytes());
+        }
+        let tag = self.db.import_bytes(data.into(), BlobFormat::Raw).await?;
+        self.runs.push(tag);
+        Ok(())
+    }
+
+    /// Import the entries of all runs in order as one blob, each encoded by
+    /// `encode`, between `header` and `footer`.
+    async fn import_merged(
+        &self,
+        runs: &[Hash],
+        header: Vec<u8>,
+        footer: Vec<u8>,
+        encode: impl Fn(&mut Vec<u8>, &str, &Hash),
+    ) -> anyhow::Result<TempTag> {
+        let (send, recv) = async_channel::bounded(4);
+        let write = async move {
+            let mut merge = merge(&self.db, runs).await?;
+            let mut chunk = header;
+            while let Some((name, hash)) = merge.next().await? {
+                encode(&mut chunk, &name, &hash);
+                if chunk.len() >= CHUNK_SIZE {
+                    let full = std::mem::take(&mut chunk);
+                    send.send(Ok(Bytes::from(full))).await?;
+                }
+            }
+            chunk.extend_from_slice(&footer);
+            send.send(Ok(Bytes::from(chunk))).await?;
+            anyhow::Ok(())
+        };
+        let import = async {
+            let (tag, _) = self
+                .db
+                .import_stream(
+                    Box::pin(recv),
+                    BlobFormat::Raw,
+                    IgnoreProgressSender::default(),
+                )
+                .await?;
+            anyhow::Ok(tag)
+        };
+        let ((), tag) = tokio::try_join!(write, import)?;
+        Ok(tag)
+    }
+}
+
+/// The entries of several runs, in order.
+struct Merge<R> {
+    runs: Vec<Run<R>>,
+    /// The next entry of each run, and the run it is from.
+    next: BTreeMap<String, (Hash, usize)>,
+}
+
+impl<R: AsyncSliceReader> Merge<R> {
+    async fn next(&mut self) -> anyhow::Result<Option<(String, Hash)>> {
+        let Some((name, (hash, index))) = self.next.pop_first() else {
+            return Ok(None);
+        };
+        if let Some((next, hash)) = self.runs[index].next().await? {
+            self.next.insert(next, (hash, index));
+        }
+        Ok(Some((name, hash)))
+    }
+}
+
+/// Start merging the runs with the given hashes.
+async fn merge(db: &impl Store, runs: &[Hash]) -> anyhow::Result<Merge<impl AsyncSliceReader>> {
+    let mut merge = Merge {
+        runs: Vec::with_capacity(runs.len()),
+        next: BTreeMap::new(),
+    };
+    for hash in runs {
+        let entry = db.get(hash).await?.context("run not found")?;
+        let mut run = Run {
+            reader: entry.data_reader().await?,
+            offset: 0,
+            data: Vec::new(),
+            pos: 0,
+        };
+        if let Some((name, hash)) = run.next().await? {
+            merge.next.insert(name, (hash, merge.runs.len()));
+        }
+        merge.runs.push(run);
+    }
+    Ok(merge)
+}
+
+/// Reads the entries of a run, a chunk at a time.
+struct Run<R> {
+    reader: R,
+    offset: u64,
+    /// What was read, decoded up to `pos`.
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: AsyncSliceReader> Run<R> {
+    async fn next(&mut self) -> anyhow::Result<Option<(String, Hash)>> {
+        loop {
+            if let Some((entry, used)) = decode_entry(&self.data[self.pos..])? {
+                self.pos += used;
+                return Ok(Some(entry));
+            }
+            let chunk = self.reader.read_at(self.offset, CHUNK_SIZE).await?;
+            anyhow::ensure!(
+                !chunk.is_empty() || self.pos == self.data.len(),
+                "truncated run"
+            );
+            if chunk.is_empty() {
+                return Ok(None);
+            }
+            self.offset += chunk.len() as u64;
+            self.data.drain(..self.pos);
+            self.pos = 0;
+            self.data.extend_from_slice(&chunk);
+        }
+    }
+}
+
+/// The entry at the start of `data` and its length, if it is complete.
+fn decode_entry(data: &[u8]) -> anyhow::Result<Option<((String, Hash), usize)>> {
+    let Some(len) = data.get(..4) else {
+        return Ok(None);
+    };
+    let len = u32::from_le_bytes(len.try_into()?) as usize;
+    let Some(name) = data.get(4..4 + len) else {
+        return Ok(None);
+    };
+    let Some(hash) = data.get(4 + len..4 + len + 32) else {
+        return Ok(None);
+    };
+    let name = String::from_utf8(name.to_vec())?;
+    let hash = Hash::from_bytes(hash.try_into()?);
+    Ok(Some(((name, hash), 4 + len + 32)))
+}
+
+/// Append a string as postcard does, prefixed with its length.
+fn push_name(data: &mut Vec<u8>, name: &str) {
+    push_varint(data, name.len() as u64);
+    data.extend_from_slice(name.as_bytes());
+}
+
+/// Append a postcard varint, seven bits at a time with the lowest first.
+fn push_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
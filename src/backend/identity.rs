@@ -0,0 +1,194 @@
+//! Named node identities, kept in the platform config directory.
+//!
+//! Each identity is a secret key in `identities/<name>.key`, hex encoded. The
+//! `identity` file names the one in use, "default" if there is none.
+use crate::interconnect::display_from_str;
+use anyhow::Context;
+use data_encoding::HEXLOWER;
+use iroh::{NodeId, SecretKey};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// The identity used if none was chosen.
+pub const DEFAULT_IDENTITY: &str = "default";
+
+/// A stored identity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    pub name: String,
+    #[serde(with = "display_from_str")]
+    pub node_id: NodeId,
+    /// Whether transfers use it unless told otherwise.
+    pub active: bool,
+}
+
+/// The secret key transfers use.
+///
+/// `IROH_SECRET` wins over everything, otherwise it is the identity `name`, or
+/// the active one, created on first use.
+pub fn secret_key(name: Option<&str>) -> anyhow::Result<SecretKey> {
+    if let Ok(secret) = std::env::var("IROH_SECRET") {
+        return SecretKey::from_str(&secret).context("invalid secret in IROH_SECRET");
+    }
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => active()?,
+    };
+    load(&name)
+}
+
+/// The directory sendme keeps its settings in.
+pub fn config_dir() -> anyhow::Result<PathBuf> {
+    let dir = dirs::config_dir().context("no config directory on this platform")?;
+    Ok(dir.join("sendme"))
+}
+
+/// The name of the active identity.
+pub fn active() -> anyhow::Result<String> {
+    match std::fs::read_to_string(config_dir()?.join("identity")) {
+        Ok(name) => Ok(name.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(DEFAULT_IDENTITY.to_string()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Make an existing identity the active one.
+pub fn set_active(name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(key_path(name)?.exists(), "no identity named {name}");
+    let dir = config_dir()?;
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("identity"), name)?;
+    Ok(())
+}
+
+/// All stored identities, sorted by name.
+pub fn list() -> anyhow::Result<Vec<Identity>> {
+    let active = active()?;
+    let dir = identities_dir()?;
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut identities = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() != Some("key".as_ref()) {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        identities.push(Identity {
+            name: name.to_string(),
+            node_id: read(&path)?.public(),
+            active: name == active,
+        });
+    }
+    identities.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(identities)
+}
+
+/// The secret key of identity `name`, created if it doesn't exist yet.
+pub fn load(name: &str) -> anyhow::Result<SecretKey> {
+    let path = key_path(name)?;
+    if path.exists() {
+        read(&path)
+    } else {
+        create(name)
+    }
+}
+
+/// Create a new identity with a random key.
+pub fn create(name: &str) -> anyhow::Result<SecretKey> {
+    let path = key_path(name)?;
+    anyhow::ensure!(!path.exists(), "identity {name} already exists");
+    let key = SecretKey::generate(rand::rngs::OsRng);
+    write(&path, &key)?;
+    Ok(key)
+}
+
+/// Replace the key of an identity with a new random one.
+///
+/// Peers that know the old node id will not recognize the new one.
+pub fn rotate(name: &str) -> anyhow::Result<SecretKey> {
+    let path = key_path(name)?;
+    anyhow::ensure!(path.exists(), "no identity named {name}");
+    let key = SecretKey::generate(rand::rngs::OsRng);
+    write(&path, &key)?;
+    Ok(key)
+}
+
+/// The secret key of an existing identity, in the form [`import`] and
+/// `IROH_SECRET` accept.
+pub fn export(name: &str) -> anyhow::Result<String> {
+    let path = key_path(name)?;
+    anyhow::ensure!(path.exists(), "no identity named {name}");
+    Ok(HEXLOWER.encode(&read(&path)?.to_bytes()))
+}
+
+/// Store an exported secret key as a new identity.
+pub fn import(name: &str, secret: &str) -> anyhow::Result<SecretKey> {
+    let path = key_path(name)?;
+    anyhow::ensure!(!path.exists(), "identity {name} already exists");
+    let key = SecretKey::from_str(secret.trim()).context("invalid secret key")?;
+    write(&path, &key)?;
+    Ok(key)
+}
+
+/// Delete an identity. The active one can't be deleted.
+pub fn remove(name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(name != active()?, "identity {name} is in use");
+    let path = key_path(name)?;
+    anyhow::ensure!(path.exists(), "no identity named {name}");
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+fn identities_dir() -> anyhow::Result<PathBuf> {
+    Ok(config_dir()?.join("identities"))
+}
+
+fn key_path(name: &str) -> anyhow::Result<PathBuf> {
+    anyhow::ensure!(
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "identity names may only contain letters, digits, - and _"
+    );
+    Ok(identities_dir()?.join(format!("{name}.key")))
+}
+
+fn read(path: &Path) -> anyhow::Result<SecretKey> {
+    let hex = std::fs::read_to_string(path)?;
+    let bytes = HEXLOWER
+        .decode(hex.trim().as_bytes())
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .with_context(|| format!("invalid key in {}", path.display()))?;
+    Ok(SecretKey::from_bytes(&bytes))
+}
+
+/// Write a key readable only by the user, replacing any previous one at once.
+fn write(path: &Path, key: &SecretKey) -> anyhow::Result<()> {
+    let dir = path.parent().context("invalid key path")?;
+    std::fs::create_dir_all(dir)?;
+    let temp = path.with_extension("key.tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temp)?;
+    file.write_all(HEXLOWER.encode(&key.to_bytes()).as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(temp, path)?;
+    Ok(())
+}
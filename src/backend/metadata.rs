@@ -0,0 +1,156 @@
+//! What a collection carries besides the names and hashes of its files.
+//!
+//! Links, directories, attributes, compression and packs are described in a
+//! JSON blob that is the last entry of the collection, named [`METADATA_NAME`].
+//! Collections without any of them have no such entry.
+use anyhow::Context;
+use bytes::Bytes;
+use filetime::FileTime;
+use iroh_blobs::{
+    format::collection::Collection,
+    store::{Map, MapEntry, Store},
+    BlobFormat, Hash, TempTag,
+};
+use iroh_io::AsyncSliceReaderExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// Name of the collection entry that holds the [`Metadata`].
+///
+/// It is the last entry of the collection. Receivers that don't know about it
+/// export it like any other file.
+pub const METADATA_NAME: &str = ".sendme-metadata.json";
+
+/// Name of the directory the packs of small files are in, as `.sendme-pack/<n>`.
+///
+/// Receivers that don't know about packs export them like any other file.
+pub const PACK_DIR: &str = ".sendme-pack";
+
+/// What a collection can't express with `(name, hash)` pairs alone.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    /// Entries that are symbolic links. Their blob holds the link target, with
+    /// `/` as separator.
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub links: BTreeSet<String>,
+    /// All directories, so empty ones are created too.
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub dirs: BTreeSet<String>,
+    /// Permissions and modification times of files and directories, by name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, Attributes>,
+    /// Entries whose blob is zstd compressed, with their original size.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub compressed: BTreeMap<String, u64>,
+    /// Entries whose blob packs several small files, like a tar without headers.
+    /// The files follow each other in the blob in the listed order.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub packed: BTreeMap<String, Vec<PackedFile>>,
+}
+
+/// A file in a pack.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackedFile {
+    pub name: String,
+    pub size: u64,
+}
+
+/// File system attributes of an entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Attributes {
+    /// Unix permission bits, like `0o755`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Modification time since the unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<Duration>,
+}
+
+impl Attributes {
+    pub fn from_metadata(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        Self { mode, modified }
+    }
+
+    /// Apply the attributes to the file or directory at `path`.
+    ///
+    /// Only permission bits are set, never setuid, setgid or sticky bits.
+    pub fn apply(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(modified) = self.modified {
+            let secs = modified.as_secs() as i64;
+            let time = FileTime::from_unix_time(secs, modified.subsec_nanos());
+            filetime::set_file_mtime(path, time)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(mode & 0o777);
+            std::fs::set_permissions(path, permissions)?;
+        }
+        Ok(())
+    }
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+            && self.dirs.is_empty()
+            && self.attributes.is_empty()
+            && self.compressed.is_empty()
+            && self.packed.is_empty()
+    }
+
+    /// Store the metadata as a blob, unless it is empty, to be the last entry
+    /// of a collection, named [`METADATA_NAME`].
+    ///
+    /// The returned tag protects the metadata blob until the collection is stored.
+    pub(crate) async fn store(&self, db: &impl Store) -> anyhow::Result<Option<TempTag>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let json = serde_json::to_vec(self)?;
+        let tag = db.import_bytes(json.into(), BlobFormat::Raw).await?;
+        Ok(Some(tag))
+    }
+
+    /// Take the metadata entry off `collection`, if it has one.
+    pub(crate) async fn split(
+        collection: Collection,
+        db: &impl Store,
+    ) -> anyhow::Result<(Collection, Self)> {
+        let Some((name, hash)) = collection.iter().last() else {
+            return Ok((collection, Self::default()));
+        };
+        if name != METADATA_NAME {
+            return Ok((collection, Self::default()));
+        }
+        let metadata = serde_json::from_slice(&read_blob(db, hash).await?)
+            .context("invalid collection metadata")?;
+        let len = collection.len() - 1;
+        let collection = collection.into_iter().take(len).collect();
+        Ok((collection, metadata))
+    }
+}
+
+/// Read a complete blob from the store into memory.
+pub(crate) async fn read_blob(db: &impl Store, hash: &Hash) -> anyhow::Result<Bytes> {
+    let entry = db.get(hash).await?.context("blob not found")?;
+    let bytes = entry.data_reader().await?.read_to_end().await?;
+    Ok(bytes)
+}
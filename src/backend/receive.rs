@@ -1,31 +1,44 @@
-use crate::backend::get_or_create_secret;
-use crate::interconnect::{ReceiveArgs, ViewProgress, ViewUpdate};
+use crate::backend::metadata::{read_blob, Attributes, Metadata, PackedFile};
+use crate::backend::report::Reporter;
+use crate::backend::scratch::ScratchDir;
+use crate::backend::{ensure_space, identity};
+use crate::interconnect::{ExportTarget, ReceiveArgs, ReceiveOutcome, TransferEvent};
+use anyhow::Context;
+use async_compression::tokio::bufread::ZstdDecoder;
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
-use iroh::{discovery::dns::DnsDiscovery, Endpoint};
+use iroh::{discovery::dns::DnsDiscovery, endpoint::Connection, Endpoint};
 use iroh_blobs::{
     format::collection::Collection,
-    get::{db::DownloadProgress, request::get_hash_seq_and_sizes},
+    get::{
+        self,
+        db::DownloadProgress,
+        fsm::{ConnectedNext, EndBlobNext},
+        request::get_hash_seq_and_sizes,
+        Stats,
+    },
+    hashseq::HashSeq,
+    protocol::GetRequest,
     provider::CustomEventSender,
     store::ExportMode,
     Hash, HashAndFormat,
 };
-use rfd::FileHandle;
+use iroh_io::TokioStreamWriter;
 use std::time::Instant;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     fmt::Display,
     path::{Path, PathBuf},
     str::FromStr,
 };
-use tokio::sync::watch::Sender;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-pub async fn receive(
-    args: ReceiveArgs,
-    view_update_sender: Sender<ViewUpdate>,
-) -> anyhow::Result<()> {
+const EXPORT_CANCELLED: &str =
+    "no target selected, export cancelled. The download is kept and will not be repeated.";
+
+pub async fn receive(args: ReceiveArgs, reporter: Reporter) -> anyhow::Result<ReceiveOutcome> {
     let ticket = args.ticket;
     let addr = ticket.node_addr().clone();
-    let secret_key = get_or_create_secret(false)?;
+    let secret_key = identity::secret_key(args.common.identity.as_deref())?;
     let mut builder = Endpoint::builder()
         .alpns(vec![])
         .secret_key(secret_key)
@@ -41,64 +54,117 @@ pub async fn receive(
         builder = builder.bind_addr_v6(addr);
     }
     let endpoint = builder.bind().await?;
-    let dir_name = format!(".sendme-get-{}", ticket.hash().to_hex());
-    let iroh_data_dir = std::env::current_dir()?.join(dir_name);
-    let db = iroh_blobs::store::fs::Store::load(&iroh_data_dir).await?;
     let mp = MultiProgress::new();
     let connect_progress = mp.add(ProgressBar::hidden());
     connect_progress.set_draw_target(ProgressDrawTarget::stderr());
     connect_progress.set_style(ProgressStyle::default_spinner());
-    connect_progress.set_message(format!("connecting to {}", addr.node_id));
+    // senders that restrict a share need to know who we are
+    connect_progress.set_message(format!(
+        "connecting to {} as {}",
+        addr.node_id,
+        endpoint.node_id()
+    ));
     let connection = endpoint.connect(addr, iroh_blobs::protocol::ALPN).await?;
+    reporter.emit(TransferEvent::Connected {
+        node_id: ticket.node_addr().node_id,
+    });
     let hash_and_format = HashAndFormat {
         hash: ticket.hash(),
         format: ticket.format(),
     };
     connect_progress.finish_and_clear();
-    let (send, recv) = async_channel::bounded(32);
-    let progress = iroh_blobs::util::progress::AsyncChannelProgressSender::new(send);
-    let (_hash_seq, sizes) =
+    let (hash_seq, sizes) =
         get_hash_seq_and_sizes(&connection, &hash_and_format.hash, 1024 * 1024 * 32).await?;
     // .map_err(show_get_error)?;
+    if args.stdout {
+        let stats = write_to_stdout(connection, &hash_seq, &sizes).await?;
+        reporter.emit(TransferEvent::Done {
+            stats: stats.clone(),
+            path: "stdout".to_string(),
+        });
+        return Ok(ReceiveOutcome {
+            stats,
+            path: None,
+            files: Vec::new(),
+        });
+    }
+
+    // an interrupted download of the same ticket continues in the same store
+    let dir_name = format!("get-{}", ticket.hash().to_hex());
+    let scratch = ScratchDir::create(&args.common, &dir_name).await?;
+    scratch.set_ticket(&ticket)?;
     let total_size = sizes.iter().sum::<u64>();
+    ensure_space(scratch.path(), total_size)?;
+    let db = iroh_blobs::store::fs::Store::load(scratch.path()).await?;
+    let (send, recv) = async_channel::bounded(32);
+    let progress = iroh_blobs::util::progress::AsyncChannelProgressSender::new(send);
     let total_files = sizes.len().saturating_sub(1);
-    let _task = tokio::spawn(show_download_progress(
+    let show_progress = tokio::spawn(show_download_progress(
         recv,
         total_size,
         total_files,
-        view_update_sender.clone(),
+        reporter.clone(),
     ));
 
     let get_conn = || async move { Ok(connection) };
     let stats = iroh_blobs::get::db::get_to_db(&db, get_conn, &hash_and_format, progress).await?;
-    view_update_sender.send(ViewUpdate::DownloadDone {
-        stats: stats.clone(),
-        path: String::new(),
-    })?;
     // .map_err(|e| show_get_error(anyhow::anyhow!(e)))?;
+    // the progress sender is dropped by now, so this only waits for the last update
+    show_progress.await.ok();
 
     let collection = Collection::load_db(&db, &hash_and_format.hash).await?;
+    let (collection, metadata) = Metadata::split(collection, &db).await?;
+    let (path, files) = export(db, collection, &metadata, &args.target, &reporter).await?;
+    reporter.emit(TransferEvent::Done {
+        stats: stats.clone(),
+        path: path.display().to_string(),
+    });
+    scratch.remove().await?;
 
-    if let Some((name, _)) = collection.iter().next() {
-        if let Some(first) = name.split('/').next() {
-            view_update_sender.send(ViewUpdate::DownloadDone {
-                stats,
-                path: first.to_string(),
-            })?;
-        }
-    }
-
-    export(db, collection).await?;
-    tokio::fs::remove_dir_all(iroh_data_dir).await?;
+    Ok(ReceiveOutcome {
+        stats,
+        path: Some(path),
+        files,
+    })
+}
 
-    Ok(())
+/// Stream the single file of a collection to stdout.
+///
+/// The data is verified chunk by chunk before it is written, so everything on
+/// stdout is correct even if the transfer fails halfway.
+async fn write_to_stdout(
+    connection: Connection,
+    hash_seq: &HashSeq,
+    sizes: &[u64],
+) -> anyhow::Result<Stats> {
+    // the first entry of the hash seq is the collection metadata
+    anyhow::ensure!(
+        sizes.len() == 2,
+        "--stdout only works for a single file, the collection has {} entries",
+        sizes.len().saturating_sub(1)
+    );
+    let hash = hash_seq.get(1).context("missing file hash")?;
+    let request = GetRequest::single(hash);
+    let connected = get::fsm::start(connection, request).next().await?;
+    let ConnectedNext::StartRoot(start) = connected.next().await? else {
+        anyhow::bail!("expected a single blob");
+    };
+    let (content, _size) = start.next().next().await?;
+    let mut stdout = tokio::io::stdout();
+    let end = content.write_all(TokioStreamWriter(&mut stdout)).await?;
+    stdout.flush().await?;
+    let EndBlobNext::Closing(closing) = end.next() else {
+        anyhow::bail!("expected a single blob");
+    };
+    let stats = closing.next().await?;
+    Ok(stats)
 }
 
 pub async fn show_download_progress(
     recv: async_channel::Receiver<DownloadProgress>,
     total_size: u64,
     total_files: usize,
-    view_update_sender: Sender<ViewUpdate>,
+    reporter: Reporter,
 ) -> anyhow::Result<()> {
     let mut total_done = 0;
     let mut sizes = BTreeMap::new();
@@ -120,12 +186,12 @@ pub async fn show_download_progress(
                     last_time = Instant::now();
                 }
 
-                view_update_sender.send(ViewUpdate::Progress(ViewProgress {
+                reporter.emit(TransferEvent::DownloadProgress {
                     total_size,
-                    bytes_per_second: speed as u64,
                     total_files,
-                    progress_value: progress,
-                }))?;
+                    offset: progress,
+                    bytes_per_second: speed as u64,
+                });
             }
             Ok(DownloadProgress::Done { id }) => {
                 total_done += sizes.remove(&id).unwrap_or_default();
@@ -145,19 +211,287 @@ pub async fn show_download_progress(
     Ok(())
 }
 
-async fn export(db: impl iroh_blobs::store::Store, collection: Collection) -> anyhow::Result<()> {
-    if is_one_file(&collection) {
-        let target = get_file_target(&collection).await?;
-        let (_, hash) = collection.iter().next().unwrap();
-        export_to_target(&db, hash, target).await?;
+/// Export the collection to the location chosen by `target`.
+///
+/// Returns the path of the exported file or top level directory, or the target
+/// directory if there are several, and the paths of all exported files.
+async fn export(
+    db: impl iroh_blobs::store::Store,
+    collection: Collection,
+    metadata: &Metadata,
+    target: &ExportTarget,
+    reporter: &Reporter,
+) -> anyhow::Result<(PathBuf, Vec<PathBuf>)> {
+    // a shared directory with a single file still needs its directories
+    if is_one_file(&collection) && metadata.dirs.is_empty() && metadata.packed.is_empty() {
+        let (name, hash) = collection.iter().next().unwrap();
+        let target = match target {
+            ExportTarget::Dialog => get_file_target(name).await?,
+            _ => get_export_path(&get_root(target, &collection).await?, name)?,
+        };
+        if metadata.links.contains(name) {
+            // a link on its own can't point into the download
+            reporter.warn(format!(
+                "not recreating link {name}, it has nothing to point to"
+            ));
+            return Ok((target, Vec::new()));
+        }
+        export_file(&db, name, hash, metadata, target.clone()).await?;
+        if let Some(attributes) = metadata.attributes.get(name) {
+            let root = target.parent().unwrap_or(&target);
+            apply_attributes(root, &target, attributes, reporter);
+        }
+        Ok((target.clone(), vec![target]))
     } else {
-        let root = get_folder_root().await?;
+        let root = get_root(target, &collection).await?;
+        let mut files = Vec::with_capacity(collection.len());
         for (name, hash) in collection.iter() {
+            if metadata.links.contains(name) {
+                continue;
+            }
+            if let Some(packed) = metadata.packed.get(name) {
+                files.extend(unpack(&db, name, hash, packed, &root).await?);
+                continue;
+            }
             let target = get_export_path(&root, name)?;
-            export_to_target(&db, hash, target).await?;
+            export_file(&db, name, hash, metadata, target.clone()).await?;
+            files.push(target);
+        }
+        // directories without files only exist in the metadata
+        for name in &metadata.dirs {
+            tokio::fs::create_dir_all(get_metadata_path(&root, name)?).await?;
+        }
+        // links last, so it is known whether their targets are directories
+        for (name, hash) in collection.iter() {
+            if metadata.links.contains(name) {
+                let links = &metadata.links;
+                files.extend(export_link(&db, hash, &root, name, links, reporter).await?);
+            }
+        }
+        // children before their parents, so nothing touches a directory after
+        // its time is set or it is made read only
+        for (name, attributes) in metadata.attributes.iter().rev() {
+            let path = get_metadata_path(&root, name)?;
+            apply_attributes(&root, &path, attributes, reporter);
+        }
+        let top_level = collection
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| !metadata.packed.contains_key(*name))
+            .chain(
+                metadata
+                    .packed
+                    .values()
+                    .flatten()
+                    .map(|file| file.name.as_str()),
+            )
+            .chain(metadata.dirs.iter().map(String::as_str))
+            .filter_map(|name| name.split('/').next())
+            .collect::<BTreeSet<_>>();
+        // several shared paths have no common top level directory but the root
+        let path = match top_level.into_iter().collect::<Vec<_>>().as_slice() {
+            [first] => root.join(first),
+            _ => root,
+        };
+        Ok((path, files))
+    }
+}
+
+/// Set the permissions and modification time of an exported file or directory.
+///
+/// Failing to do so is not worth losing the download over, so it only warns.
+///
+/// Links are never followed, they could lead outside of `root`.
+fn apply_attributes(root: &Path, path: &Path, attributes: &Attributes, reporter: &Reporter) {
+    let is_link = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink());
+    let inside = path
+        .parent()
+        .and_then(|parent| parent.canonicalize().ok())
+        .zip(root.canonicalize().ok())
+        .is_some_and(|(parent, root)| parent.starts_with(root));
+    if is_link || !inside {
+        reporter.warn(format!(
+            "not setting attributes of {}, it is a link or below one",
+            path.display()
+        ));
+        return;
+    }
+    if let Err(e) = attributes.apply(path) {
+        reporter.warn(format!(
+            "could not set attributes of {}: {e}",
+            path.display()
+        ));
+    }
+}
+
+/// The path of a directory or attribute entry of the metadata below `root`.
+///
+/// The names come from the sender, so they must not lead outside of `root`.
+fn get_metadata_path(root: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    anyhow::ensure!(
+        name.split('/').all(|part| !matches!(part, "" | "." | "..")),
+        "invalid name {name} in collection metadata"
+    );
+    get_export_path(root, name)
+}
+
+/// Recreate a preserved symbolic link, unless it points outside of its top level
+/// directory.
+async fn export_link(
+    db: &impl iroh_blobs::store::Store,
+    hash: &Hash,
+    root: &Path,
+    name: &str,
+    links: &BTreeSet<String>,
+    reporter: &Reporter,
+) -> anyhow::Result<Option<PathBuf>> {
+    let target = String::from_utf8(read_blob(db, hash).await?.to_vec())
+        .with_context(|| format!("link {name} has an invalid target"))?;
+    let Some(resolved) = resolve_link(name, &target, links) else {
+        reporter.warn(format!(
+            "not recreating link {name}, its target {target} is outside of the download"
+        ));
+        return Ok(None);
+    };
+    let link = get_export_path(root, name)?;
+    if tokio::fs::symlink_metadata(&link).await.is_ok() {
+        anyhow::bail!("target {} already exists", link.display());
+    }
+    if let Some(parent) = link.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    symlink(&target, &link, root.join(resolved).is_dir())?;
+    Ok(Some(link))
+}
+
+/// Where the link `name` points to, relative to the export root.
+///
+/// Returns `None` for absolute targets, targets outside of the top level
+/// directory of the link and targets that go through one of the other `links`,
+/// since those could lead anywhere.
+fn resolve_link(name: &str, target: &str, links: &BTreeSet<String>) -> Option<PathBuf> {
+    let mut resolved = name.split('/').collect::<Vec<_>>();
+    let top_level = resolved.first().copied()?;
+    resolved.pop();
+    if target.starts_with('/') {
+        return None;
+    }
+    let is_link = |resolved: &[&str]| links.contains(&resolved.join("/"));
+    if (1..=resolved.len()).any(|len| is_link(&resolved[..len])) {
+        return None;
+    }
+    for (i, part) in target.split('/').enumerate() {
+        // a link at the end is fine, it was checked on its own
+        if i > 0 && is_link(&resolved) {
+            return None;
+        }
+        match part {
+            "" | "." => {}
+            ".." => {
+                resolved.pop()?;
+            }
+            // windows drive prefixes and separators
+            part if part.contains(':') || part.contains('\\') => return None,
+            part => resolved.push(part),
+        }
+    }
+    if resolved.first() != Some(&top_level) {
+        return None;
+    }
+    Some(resolved.iter().collect())
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, link: &Path, _is_dir: bool) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &str, link: &Path, is_dir: bool) -> std::io::Result<()> {
+    let target = target.replace('/', "\\");
+    if is_dir {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+/// Write the files of the pack `name` to where they belong below `root`.
+async fn unpack(
+    db: &impl iroh_blobs::store::Store,
+    name: &str,
+    hash: &Hash,
+    packed: &[PackedFile],
+    root: &Path,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let data = read_blob(db, hash).await?;
+    anyhow::ensure!(
+        packed.iter().map(|file| file.size).sum::<u64>() == data.len() as u64,
+        "pack {name} does not match its index"
+    );
+    let mut files = Vec::with_capacity(packed.len());
+    let mut offset = 0;
+    for file in packed {
+        // the names come from the metadata, like those of directories
+        let target = get_metadata_path(root, &file.name)?;
+        if target.exists() {
+            anyhow::bail!("target {} already exists", target.display());
         }
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let end = offset + file.size as usize;
+        tokio::fs::write(&target, &data[offset..end]).await?;
+        offset = end;
+        files.push(target);
     }
+    Ok(files)
+}
 
+/// Export the file `name`, decompressing it if the sender compressed it.
+async fn export_file(
+    db: &impl iroh_blobs::store::Store,
+    name: &str,
+    hash: &Hash,
+    metadata: &Metadata,
+    target: PathBuf,
+) -> anyhow::Result<()> {
+    let Some(size) = metadata.compressed.get(name) else {
+        return export_to_target(db, hash, target).await;
+    };
+    if target.exists() {
+        anyhow::bail!("target {} already exists", target.display());
+    }
+    let file_name = target.file_name().context("invalid target")?;
+    let compressed = target.with_file_name(format!(".{}.zst", file_name.to_string_lossy()));
+    export_to_target(db, hash, compressed.clone()).await?;
+    let result = decompress(&compressed, &target, *size).await;
+    tokio::fs::remove_file(&compressed).await?;
+    if result.is_err() {
+        tokio::fs::remove_file(&target).await.ok();
+    }
+    result
+}
+
+/// Decompress the zstd file at `source` into a new file at `target`.
+///
+/// The data was verified in its compressed form, so only the size is checked.
+async fn decompress(source: &Path, target: &Path, size: u64) -> anyhow::Result<()> {
+    let source = tokio::fs::File::open(source).await?;
+    // one byte more than announced is enough to tell that the data is wrong
+    let mut decoder = ZstdDecoder::new(tokio::io::BufReader::new(source)).take(size + 1);
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(target)
+        .await?;
+    let written = tokio::io::copy(&mut decoder, &mut file).await?;
+    file.flush().await?;
+    anyhow::ensure!(
+        written == size,
+        "{} decompressed to {written} bytes instead of {size}",
+        target.display()
+    );
     Ok(())
 }
 
@@ -184,35 +518,40 @@ async fn export_to_target(
     Ok(())
 }
 
+/// Resolve the directory the collection gets exported into, creating it if needed.
+async fn get_root(target: &ExportTarget, collection: &Collection) -> anyhow::Result<PathBuf> {
+    let root = match target {
+        ExportTarget::Dir(dir) => dir.clone(),
+        ExportTarget::Dialog => get_folder_root().await?,
+        ExportTarget::Callback(pick) => pick(collection).context(EXPORT_CANCELLED)?,
+    };
+    // the store can only export to absolute paths
+    let root = std::path::absolute(root)?;
+    tokio::fs::create_dir_all(&root).await?;
+    Ok(root)
+}
+
 async fn get_folder_root() -> anyhow::Result<PathBuf> {
     let root = std::env::current_dir()?;
-    let file_option = rfd::AsyncFileDialog::new()
+    let handle = rfd::AsyncFileDialog::new()
         .set_directory(root.as_path())
         .set_title("Save to...")
         .pick_folder()
-        .await;
-
-    if let Some(handle) = file_option {
-        Ok(handle.path().to_path_buf())
-    } else {
-        Ok(root)
-    }
+        .await
+        .context(EXPORT_CANCELLED)?;
+    Ok(handle.path().to_path_buf())
 }
 
-async fn get_file_target(collection: &Collection) -> anyhow::Result<PathBuf> {
+async fn get_file_target(name: &str) -> anyhow::Result<PathBuf> {
     let root = std::env::current_dir()?;
-    let (name, _) = collection.iter().next().unwrap();
-    let file_option = rfd::AsyncFileDialog::new()
+    let handle = rfd::AsyncFileDialog::new()
         .set_file_name(name)
         .set_directory(root.as_path())
         .set_title("Save to...")
         .save_file()
-        .await;
-    if let Some(handle) = file_option {
-        Ok(handle.path().to_path_buf())
-    } else {
-        get_export_path(&root, name)
-    }
+        .await
+        .context(EXPORT_CANCELLED)?;
+    Ok(handle.path().to_path_buf())
 }
 
 fn is_one_file(collection: &Collection) -> bool {
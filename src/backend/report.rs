@@ -0,0 +1,88 @@
+use crate::interconnect::{TransferEvent, ViewUpdate};
+use std::fmt::Display;
+use std::io::Write;
+use tokio::sync::watch::Sender;
+
+/// Hands transfer events to the view, an event channel and, in JSON mode, stdout.
+#[derive(Debug, Clone, Default)]
+pub struct Reporter {
+    view_update_sender: Option<Sender<ViewUpdate>>,
+    event_sender: Option<async_channel::Sender<TransferEvent>>,
+    /// How many events may wait in the channel before progress is dropped.
+    event_buffer: usize,
+    json: bool,
+}
+
+impl Reporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep a view up to date with the events that have a [`ViewUpdate`].
+    pub fn view(mut self, view_update_sender: Sender<ViewUpdate>) -> Self {
+        self.view_update_sender = Some(view_update_sender);
+        self
+    }
+
+    /// Forward every event to a channel.
+    ///
+    /// Progress events are dropped while `buffer` events are waiting, a later
+    /// one has the same totals anyway. All other events are forwarded unless a
+    /// bounded channel is full, so events nobody reads don't pile up forever.
+    pub fn events(
+        mut self,
+        event_sender: async_channel::Sender<TransferEvent>,
+        buffer: usize,
+    ) -> Self {
+        self.event_sender = Some(event_sender);
+        self.event_buffer = buffer;
+        self
+    }
+
+    /// Print every event as a line of JSON to stdout.
+    pub fn json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    /// Report an event.
+    pub fn emit(&self, event: TransferEvent) {
+        if self.json {
+            // one object per line, so a reader can parse line by line
+            let line = serde_json::to_string(&event).expect("events are serializable");
+            let mut stdout = std::io::stdout().lock();
+            writeln!(stdout, "{line}").ok();
+            stdout.flush().ok();
+        }
+        if let Some(sender) = &self.view_update_sender {
+            if let Some(update) = event.view_update() {
+                sender.send_replace(update);
+            }
+        }
+        if let Some(sender) = &self.event_sender {
+            if !event.is_progress() || sender.len() < self.event_buffer {
+                sender.try_send(event).ok();
+            }
+        }
+    }
+
+    /// Report something that was left out, printed to stderr unless in JSON mode.
+    pub fn warn(&self, message: impl Display) {
+        let message = message.to_string();
+        if !self.json {
+            eprintln!("warning: {message}");
+        }
+        self.emit(TransferEvent::Warning { message });
+    }
+
+    /// Print a message for humans. Suppressed in JSON mode to keep stdout parseable.
+    pub fn log(&self, message: impl Display) {
+        if !self.json {
+            println!("{message}");
+        }
+    }
+}
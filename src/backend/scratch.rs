@@ -0,0 +1,210 @@
+//! Scratch stores of running transfers, and the ones left behind by a crash.
+//!
+//! Each store is a directory in `scratch` of the data directory, with a lock
+//! file that is held while a transfer uses it. Older versions kept their stores
+//! as `.sendme-*` in the current directory, those are found as well.
+use crate::backend::{create_data_dir, data_dir};
+use crate::interconnect::CommonArgs;
+use anyhow::Context;
+use fs2::FileExt;
+use iroh_blobs::ticket::BlobTicket;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const SCRATCH: &str = "scratch";
+const LOCK_NAME: &str = "lock";
+const TICKET_NAME: &str = "ticket";
+
+/// The store directory of a running transfer, locked until it is removed or dropped.
+#[derive(Debug)]
+pub(crate) struct ScratchDir {
+    path: PathBuf,
+    lock: Mutex<Option<File>>,
+}
+
+impl ScratchDir {
+    /// Create the scratch directory `name`, or take over the one a previous
+    /// transfer left behind.
+    pub async fn create(common: &CommonArgs, name: &str) -> anyhow::Result<Self> {
+        let path = create_data_dir(common, &Path::new(SCRATCH).join(name)).await?;
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(LOCK_NAME))?;
+        lock.try_lock_exclusive()
+            .with_context(|| format!("{} is used by another transfer", path.display()))?;
+        Ok(Self {
+            path,
+            lock: Mutex::new(Some(lock)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remember the ticket of a download, so it can be resumed if it is interrupted.
+    pub fn set_ticket(&self, ticket: &BlobTicket) -> anyhow::Result<()> {
+        std::fs::write(self.path.join(TICKET_NAME), ticket.to_string())?;
+        Ok(())
+    }
+
+    /// Delete the directory with everything in it.
+    pub async fn remove(&self) -> anyhow::Result<()> {
+        // windows can't delete open files
+        drop(self.lock.lock().unwrap().take());
+        tokio::fs::remove_dir_all(&self.path).await?;
+        Ok(())
+    }
+}
+
+/// What a scratch store was used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum LeftoverKind {
+    #[display("upload")]
+    Send,
+    #[display("download")]
+    Receive,
+}
+
+/// A scratch store that no running transfer uses.
+#[derive(Debug, Clone)]
+pub struct Leftover {
+    pub path: PathBuf,
+    pub kind: LeftoverKind,
+    /// Total size of all files in it in bytes.
+    pub size: u64,
+    /// When something in it was last written, in seconds since the unix epoch.
+    pub modified: u64,
+    /// The ticket of an interrupted download, to resume it.
+    pub ticket: Option<BlobTicket>,
+}
+
+/// The scratch stores no running transfer uses, in the data directory and in
+/// the current directory, oldest first.
+///
+/// `custom` is the data directory to look in, like for [`data_dir`].
+pub fn leftovers(custom: Option<&Path>) -> anyhow::Result<Vec<Leftover>> {
+    let mut leftovers = Vec::new();
+    for (path, kind) in stores(custom)? {
+        if is_locked(&path)? {
+            continue;
+        }
+        let (size, modified) = usage(&path)?;
+        let ticket = std::fs::read_to_string(path.join(TICKET_NAME))
+            .ok()
+            .and_then(|ticket| BlobTicket::from_str(ticket.trim()).ok());
+        leftovers.push(Leftover {
+            path,
+            kind,
+            size,
+            modified: modified
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            ticket,
+        });
+    }
+    leftovers.sort_by_key(|leftover| leftover.modified);
+    Ok(leftovers)
+}
+
+/// Delete a scratch store, unless a transfer is using it.
+///
+/// Only the stores [`leftovers`] looks at can be deleted, a directory that just
+/// happens to be called `send-*` elsewhere is refused.
+pub fn remove_leftover(custom: Option<&Path>, path: &Path) -> anyhow::Result<()> {
+    let canonical = std::fs::canonicalize(path).ok();
+    let is_store = stores(custom)?
+        .into_iter()
+        .any(|(store, _)| std::fs::canonicalize(store).ok() == canonical);
+    anyhow::ensure!(
+        canonical.is_some() && is_store,
+        "{} is not a sendme store",
+        path.display()
+    );
+    anyhow::ensure!(!is_locked(path)?, "{} is in use", path.display());
+    std::fs::remove_dir_all(path)?;
+    Ok(())
+}
+
+/// The stores in the scratch directory, and those of older versions in the
+/// current directory, whether they are in use or not.
+fn stores(custom: Option<&Path>) -> anyhow::Result<Vec<(PathBuf, LeftoverKind)>> {
+    let mut stores = Vec::new();
+    let scratch = data_dir(custom)?.join(SCRATCH);
+    for path in entries(&scratch)? {
+        if let Some(kind) = kind(&path, "") {
+            stores.push((path, kind));
+        }
+    }
+    // stores of older versions
+    if let Ok(cwd) = std::env::current_dir() {
+        for path in entries(&cwd).unwrap_or_default() {
+            if let Some(kind) = kind(&path, ".sendme-") {
+                stores.push((path, kind));
+            }
+        }
+    }
+    stores.retain(|(path, _)| path.is_dir());
+    Ok(stores)
+}
+
+/// The kind of store `path` is, if its name is `prefix` followed by `send-` or `get-`.
+fn kind(path: &Path, prefix: &str) -> Option<LeftoverKind> {
+    let name = path.file_name()?.to_str()?.strip_prefix(prefix)?;
+    if name.starts_with("send-") {
+        Some(LeftoverKind::Send)
+    } else if name.starts_with("get-") {
+        Some(LeftoverKind::Receive)
+    } else {
+        None
+    }
+}
+
+fn entries(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        paths.push(entry?.path());
+    }
+    Ok(paths)
+}
+
+/// Whether a running transfer holds the lock of the store at `path`.
+pub(crate) fn is_locked(path: &Path) -> anyhow::Result<bool> {
+    let lock = match File::open(path.join(LOCK_NAME)) {
+        Ok(lock) => lock,
+        // stores of older versions have no lock
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    // the lock is released when the file is closed
+    Ok(lock.try_lock_exclusive().is_err())
+}
+
+/// The total size of the files below `path`, and when the newest of them was modified.
+pub(crate) fn usage(path: &Path) -> anyhow::Result<(u64, SystemTime)> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    let mut modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    if !metadata.is_dir() {
+        return Ok((metadata.len(), modified));
+    }
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let (entry_size, entry_modified) = usage(&entry?.path())?;
+        size += entry_size;
+        modified = modified.max(entry_modified);
+    }
+    Ok((size, modified))
+}
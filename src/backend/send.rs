@@ -1,197 +1,839 @@
+use crate::backend::cache::{CacheKey, HashCache};
+use crate::backend::collection::CollectionBuilder;
+use crate::backend::metadata::{Attributes, Metadata, PackedFile, METADATA_NAME, PACK_DIR};
+use crate::backend::print_hash;
+use crate::backend::report::Reporter;
+use crate::backend::share::ShareManager;
+use crate::backend::upload::PeerUpload;
 use crate::interconnect::{
-     AddrInfoOptions, SendArgs,  ViewUpdate,
+    Approval, ImportModeOption, ImportOptions, SendArgs, SendOutcome, SymlinkPolicy, TransferEvent,
 };
 use anyhow::Context;
+use async_compression::tokio::bufread::ZstdEncoder;
 use console::style;
-use data_encoding::HEXLOWER;
 use futures_buffered::BufferedStreamExt;
-use indicatif::{
-    HumanBytes,MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
-};
-use iroh::{
-    discovery::{ pkarr::PkarrPublisher},
-    Endpoint,
-};
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use iroh::NodeId;
 use iroh_blobs::{
     format::collection::Collection,
-    net_protocol::Blobs,
-    provider::CustomEventSender,
-    store::{ ImportMode, ImportProgress},
-    ticket::BlobTicket,
-    BlobFormat, TempTag,
+    store::{EntryStatus, ImportMode, ImportProgress, MapMut},
+    util::progress::{AsyncChannelProgressSender, IdGenerator, ProgressSender},
+    BlobFormat, Hash, HashAndFormat, TempTag,
 };
-use n0_future::{StreamExt};
-use rand::random;
+use n0_future::StreamExt;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::BTreeMap,
-    fmt::Display,
+    collections::{BTreeMap, BTreeSet},
     path::{Component, Path, PathBuf},
-    str::FromStr,
-    time::Duration,
+    sync::Mutex,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
-use tokio::sync::watch::{Receiver, Sender};
-use walkdir::WalkDir;
-use crate::backend::{apply_options, get_or_create_secret, print_hash};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::watch::Receiver;
+use tokio_util::io::ReaderStream;
+
+/// How often the peers of a share are checked for progress.
+pub(crate) const PEER_POLL: Duration = Duration::from_millis(250);
+
+/// How often import progress is reported while a file is hashed.
+const IMPORT_REPORT: Duration = Duration::from_millis(100);
+
+/// How many files the walk of an import may find before they are hashed.
+const WALK_AHEAD: usize = 1024;
+
+/// How many bytes of a file are compressed to decide whether to compress it.
+const COMPRESS_SAMPLE: u64 = 64 * 1024;
+
+/// Files below this size are packed, if the import packs small files.
+const PACK_LIMIT: u64 = 64 * 1024;
+
+/// How many bytes of small files go into one pack.
+const PACK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// How many files go into one pack at most, so empty and tiny files don't make
+/// packs with a huge index.
+const PACK_FILES: usize = 1024;
 
 pub async fn send(
     args: SendArgs,
-    view_update_sender: Sender<ViewUpdate>,
+    reporter: Reporter,
     mut cancel_receiver: Receiver<bool>,
-) -> anyhow::Result<()> {
-    let secret_key = get_or_create_secret(false)?;
-    // create a magicsocket endpoint
-    let mut builder = Endpoint::builder()
-        .alpns(vec![iroh_blobs::protocol::ALPN.to_vec()])
-        .secret_key(secret_key)
-        .relay_mode(args.common.relay.into());
-    if args.ticket_type == AddrInfoOptions::Id {
-        builder =
-            builder.add_discovery(|secret_key| Some(PkarrPublisher::n0_dns(secret_key.clone())));
-    }
-    if let Some(addr) = args.common.magic_ipv4_addr {
-        builder = builder.bind_addr_v4(addr);
-    }
-    if let Some(addr) = args.common.magic_ipv6_addr {
-        builder = builder.bind_addr_v6(addr);
-    }
+    mut approvals: UnboundedReceiver<(NodeId, Approval)>,
+) -> anyhow::Result<SendOutcome> {
+    let shares = ShareManager::spawn(&args.common, args.ticket_type).await?;
+    let add = shares.add(
+        args.paths.clone(),
+        args.name,
+        &args.import,
+        &args.access,
+        &args.expiry,
+        reporter.clone(),
+    );
+    let share = tokio::select! {
+        share = add => share?,
+        _ = cancel_receiver.wait_for(|cancelled| *cancelled) => {
+            // the import stops, and its store goes with the shutdown
+            shares.shutdown().await?;
+            reporter.emit(TransferEvent::Stopped);
+            anyhow::bail!("cancelled while importing");
+        }
+    };
+    let imported = match args.paths.as_slice() {
+        [path] => {
+            let entry_type = if path == Path::new("-") {
+                "stdin"
+            } else if path.is_file() {
+                "file"
+            } else {
+                "directory"
+            };
+            format!("{} {}", entry_type, path.display())
+        }
+        paths => format!("{} paths", paths.len()),
+    };
+    let size = match share.compressed_size {
+        Some(compressed) => format!(
+            "{} ({} compressed)",
+            HumanBytes(share.size),
+            HumanBytes(compressed)
+        ),
+        None => HumanBytes(share.size).to_string(),
+    };
+    reporter.log(format!(
+        "imported {}, {}, hash {}",
+        imported,
+        size,
+        print_hash(&share.hash, args.common.format)
+    ));
+    reporter.emit(TransferEvent::TicketIssued {
+        ticket: share.ticket.clone(),
+        hash: print_hash(&share.hash, args.common.format),
+        size: share.size,
+        files: share.files,
+    });
 
-    // use a flat store - todo: use a partial in mem store instead
-    let suffix = random::<[u8; 16]>();
-    let cwd = std::env::current_dir()?;
-    let blobs_data_dir = cwd.join(format!(".sendme-send-{}", HEXLOWER.encode(&suffix)));
-    if blobs_data_dir.exists() {
-        println!(
-            "can not share twice from the same directory: {}",
-            cwd.display(),
-        );
-        std::process::exit(1);
+    // Wait for exit, reporting the peers in the meantime
+    let mut peers = Vec::new();
+    let mut stale = 0;
+    let mut poll = tokio::time::interval(PEER_POLL);
+    loop {
+        tokio::select! {
+            res = cancel_receiver.changed() => {
+                if res.is_err() || *cancel_receiver.borrow() {
+                    break;
+                }
+            }
+            _ = poll.tick() => {
+                let Some(share) = shares.get(share.id) else {
+                    reporter.log("the share expired");
+                    break;
+                };
+                report_peers(&reporter, &mut peers, share.peers);
+                report_stale(&reporter, &mut stale, &share.stale);
+            }
+            Some((node_id, approval)) = approvals.recv() => {
+                if let Err(e) = shares.decide(share.id, node_id, approval) {
+                    reporter.warn(e);
+                }
+            }
+        }
     }
 
-    tokio::fs::create_dir_all(&blobs_data_dir).await?;
+    reporter.log("shutting down");
+    shares.shutdown().await?;
+    reporter.emit(TransferEvent::Stopped);
 
-    let endpoint = builder.bind().await?;
-    // let ps = SendStatus::new();
-    let blobs = Blobs::persistent(&blobs_data_dir)
-        .await?
-        // .events(ps.new_client().into())
-        .build(&endpoint);
+    Ok(SendOutcome {
+        ticket: share.ticket,
+        hash: share.hash,
+        size: share.size,
+        files: share.files,
+    })
+}
 
-    let router = iroh::protocol::Router::builder(endpoint)
-        .accept(iroh_blobs::ALPN, blobs.clone())
-        .spawn()
-        .await?;
+/// Emit a [`TransferEvent::PeerProgress`] for each peer that changed since `previous`.
+pub(crate) fn report_peers(
+    reporter: &Reporter,
+    previous: &mut Vec<PeerUpload>,
+    current: Vec<PeerUpload>,
+) {
+    for peer in &current {
+        if !previous.contains(peer) {
+            reporter.emit(TransferEvent::PeerProgress(peer.clone()));
+        }
+    }
+    *previous = current;
+}
 
-    let path = args.path;
-    let (temp_tag, size, collection) = import(path.clone(), blobs.store().clone()).await?;
-    let hash = *temp_tag.hash();
-
-    // wait for the endpoint to figure out its address before making a ticket
-    let _ = router.endpoint().home_relay().initialized().await?;
-
-    // make a ticket
-    let mut addr = router.endpoint().node_addr().await?;
-    apply_options(&mut addr, args.ticket_type);
-    let ticket = BlobTicket::new(addr, hash, BlobFormat::HashSeq)?;
-    let entry_type = if path.is_file() { "file" } else { "directory" };
-    println!(
-        "imported {} {}, {}, hash {}",
-        entry_type,
-        path.display(),
-        HumanBytes(size),
-        print_hash(&hash, args.common.format)
-    );
-    view_update_sender.send(ViewUpdate::Ticket(ticket.clone()))?;
+/// Warn about the files that went stale since the first `reported` ones.
+pub(crate) fn report_stale(reporter: &Reporter, reported: &mut usize, stale: &[PathBuf]) {
+    for path in stale.iter().skip(*reported) {
+        reporter.warn(format!(
+            "{} changed and is no longer served",
+            path.display()
+        ));
+    }
+    *reported = stale.len();
+}
 
-    drop(temp_tag);
+/// A collection imported into the database.
+#[derive(Debug)]
+pub(crate) struct Imported {
+    /// Refers to the collection, so it is not garbage collected.
+    pub tag: TempTag,
+    /// Total size of all files in bytes.
+    pub size: u64,
+    /// Total size of all blobs in bytes, if some files are compressed.
+    pub compressed_size: Option<u64>,
+    /// Files and links in the collection, without the metadata.
+    pub files: usize,
+    /// What was left out, for the user.
+    pub warnings: Vec<String>,
+    /// The files that are served from where they are, as they were when they
+    /// were imported.
+    pub sources: Vec<Source>,
+}
 
-    // Wait for exit
-    // tokio::signal::ctrl_c().await?;
-    while cancel_receiver.changed().await.is_ok() {
-        if *cancel_receiver.borrow() {
-            break;
-        }
-    }
+/// A shared file as it was when it was imported, to notice later changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Source {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Modification time since the unix epoch.
+    pub modified: Option<Duration>,
+    /// The blob of the file in the collection.
+    #[serde(default)]
+    pub hash: Option<Hash>,
+}
 
-    println!("shutting down");
-    tokio::time::timeout(Duration::from_secs(2), router.shutdown()).await??;
-    tokio::fs::remove_dir_all(blobs_data_dir).await?;
-    view_update_sender.send(ViewUpdate::Nothing)?;
+impl Source {
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()),
+            hash: None,
+        })
+    }
 
-    Ok(())
+    /// Whether the file is still there, with the same size and modification time.
+    pub fn is_unchanged(&self) -> bool {
+        Self::read(&self.path)
+            .is_ok_and(|current| current.size == self.size && current.modified == self.modified)
+    }
 }
 
-/// Import from a file or directory into the database.
+/// Import files and directories into the database.
+///
+/// The returned tag always refers to a collection. Each input is named like its
+/// last path component at the top level of the collection, so a single file
+/// becomes a collection with a single blob, named like the file.
+///
+/// Directories contribute the files in them that pass `options`, below their name.
+/// With `options.compress` every file whose start gets smaller is stored compressed.
+/// Other files are referenced where they are, unless `options.import_mode` asks
+/// for a snapshot. With `options.pack` small files are copied into packs instead,
+/// see [`PACK_DIR`].
 ///
-/// The returned tag always refers to a collection. If the input is a file, this
-/// is a collection with a single blob, named like the file.
+/// Hashing starts with the first file the walk finds, and the walk stays at most
+/// [`WALK_AHEAD`] files ahead. The names and hashes of the imported files go to
+/// the database in sorted runs, see [`CollectionBuilder`], so the collection is
+/// sorted by name whatever order the files are hashed in, without keeping it in
+/// memory. What is still kept per file is its source, if it is referenced, and
+/// the links, directories and attributes the walk finds for the metadata.
 ///
-/// If the input is a directory, the collection contains all the files in the
-/// directory.
-async fn import(
-    path: PathBuf,
+/// Referenced files that are unchanged since `cache` saw them are not hashed
+/// again, if their blob is still in the database. The others are added to it.
+///
+/// Every blob is added to `stored` as soon as it is in the database, so an import
+/// that fails or is cancelled can be removed again.
+pub(crate) async fn import(
+    paths: Vec<PathBuf>,
+    options: &ImportOptions,
     db: impl iroh_blobs::store::Store,
-) -> anyhow::Result<(TempTag, u64, Collection)> {
-    let path = path.canonicalize()?;
-    anyhow::ensure!(path.exists(), "path {} does not exist", path.display());
-    let root = path.parent().context("context get parent")?;
-    // walkdir also works for files, so we don't need to special case them
-    let files = WalkDir::new(path.clone()).into_iter();
-    // flatten the directory structure into a list of (name, path) pairs.
-    // ignore symlinks.
-    let data_sources: Vec<(String, PathBuf)> = files
-        .map(|entry| {
-            let entry = entry?;
-            if !entry.file_type().is_file() {
-                // Skip symlinks. Directories are handled by WalkDir.
-                return Ok(None);
-            }
-            let path = entry.into_path();
-            let relative = path.strip_prefix(root)?;
-            let name = canonical_path_to_string(relative, true)?;
-            anyhow::Ok(Some((name, path)))
+    reporter: Reporter,
+    cache: Option<&HashCache>,
+    stored: &Mutex<BTreeSet<Hash>>,
+) -> anyhow::Result<Imported> {
+    let mode = match options.import_mode {
+        ImportModeOption::Reference => ImportMode::TryReference,
+        ImportModeOption::Snapshot => ImportMode::Copy,
+    };
+    let reference = options.import_mode == ImportModeOption::Reference;
+    // copies stay in the database only as long as they are shared
+    let cache = cache.filter(|_| reference);
+    // the walk runs ahead of the hashing, but only so far
+    let (found, queue) = tokio::sync::mpsc::channel(WALK_AHEAD);
+    let walk = {
+        let options = options.clone();
+        tokio::task::spawn_blocking(move || {
+            let enqueue = |entry: Found| {
+                found
+                    .blocking_send(entry)
+                    .map_err(|_| anyhow::anyhow!("the import stopped"))
+            };
+            let mut packer = Packer::default();
+            let sources = data_sources(paths, &options, |name, path| {
+                if options.pack {
+                    let size = std::fs::metadata(&path)?.len();
+                    if size < PACK_LIMIT {
+                        return packer.add(name, path, size).map_or(Ok(()), enqueue);
+                    }
+                }
+                enqueue(Found::File(name, path))
+            })?;
+            packer.take().map_or(Ok(()), enqueue)?;
+            anyhow::Ok(sources)
         })
-        .filter_map(Result::transpose)
-        .collect::<anyhow::Result<Vec<_>>>()?;
+    };
+    let files = futures_lite::stream::unfold(queue, |mut queue| async move {
+        let file = queue.recv().await?;
+        Some((file, queue))
+    });
     let (send, recv) = async_channel::bounded(32);
     let progress = iroh_blobs::util::progress::AsyncChannelProgressSender::new(send);
-    let show_progress = tokio::spawn(show_ingest_progress(recv));
-    // import all the files, using num_cpus workers, return names and temp tags
-    let mut names_and_tags = futures_lite::stream::iter(data_sources)
-        .map(|(name, path)| {
+    let show_progress = tokio::spawn(show_ingest_progress(recv, reporter.clone()));
+    // import the files as they are found, using num_cpus workers
+    let mut imports = files
+        .map(|found| {
             let db = db.clone();
             let progress = progress.clone();
+            let compress = options.compress;
             async move {
-                let (temp_tag, file_size) = db
-                    .import_file(path, ImportMode::TryReference, BlobFormat::Raw, progress)
+                let (name, path) = match found {
+                    Found::File(name, path) => (name, path),
+                    Found::Pack(name, files) => {
+                        return import_pack(&db, &progress, name, files).await;
+                    }
+                };
+                // before hashing, so changes during the import are noticed later
+                let source = if reference {
+                    Some(Source::read(&path)?)
+                } else {
+                    None
+                };
+                // incompressible data is sent as is
+                if compress && compresses(&path).await? {
+                    let original_size = tokio::fs::metadata(&path).await?.len();
+                    let (tag, size) = import_compressed(&db, &path, progress.clone()).await?;
+                    let file = ImportedFile {
+                        tag,
+                        size,
+                        original_size: Some(original_size),
+                        source,
+                        packed: Vec::new(),
+                    };
+                    return anyhow::Ok((name, file));
+                }
+                let key = match cache {
+                    Some(_) => Some(CacheKey::read(&path)?),
+                    None => None,
+                };
+                let cached = key
+                    .as_ref()
+                    .zip(cache)
+                    .and_then(|(key, cache)| Some((cache.get(key)?, key.size)));
+                if let Some((hash, size)) = cached {
+                    // unless the blob was deleted when the file went stale
+                    if matches!(db.entry_status(&hash).await?, EntryStatus::Complete) {
+                        report_done(&progress, &path.display().to_string(), size, hash).await?;
+                        let tag = db.temp_tag(HashAndFormat::raw(hash));
+                        let file = ImportedFile {
+                            tag,
+                            size,
+                            original_size: None,
+                            source,
+                            packed: Vec::new(),
+                        };
+                        return anyhow::Ok((name, file));
+                    }
+                }
+                let (tag, size) = db
+                    .import_file(path, mode, BlobFormat::Raw, progress)
                     .await?;
-                anyhow::Ok((name, temp_tag, file_size))
+                if let (Some(cache), Some(key)) = (cache, key) {
+                    cache.insert(key, *tag.hash());
+                }
+                let file = ImportedFile {
+                    tag,
+                    size,
+                    original_size: None,
+                    source,
+                    packed: Vec::new(),
+                };
+                anyhow::Ok((name, file))
             }
         })
-        .buffered_unordered(num_cpus::get())
-        .collect::<Vec<_>>()
-        .await
-        .into_iter()
-        .collect::<anyhow::Result<Vec<_>>>()?;
+        .buffered_unordered(num_cpus::get());
+    // sorted by name, in whatever order the hashing finishes
+    let mut entries = Entries {
+        collection: CollectionBuilder::new(db.clone()),
+        metadata: Metadata::default(),
+        sources: Vec::new(),
+        files: 0,
+        size: 0,
+        stored_size: 0,
+    };
+    while let Some(imported) = imports.next().await {
+        let (name, file) = imported?;
+        stored.lock().unwrap().insert(*file.tag.hash());
+        entries.add(name, file).await?;
+    }
+    drop(imports);
     drop(progress);
-    names_and_tags.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
-    // total size of all files
-    let size = names_and_tags.iter().map(|(_, _, size)| *size).sum::<u64>();
-    // collect the (name, hash) tuples into a collection
-    // we must also keep the tags around so the data does not get gced.
-    let (collection, tags) = names_and_tags
-        .into_iter()
-        .map(|(name, tag, _)| ((name, *tag.hash()), tag))
-        .unzip::<_, _, Collection, Vec<_>>();
-    let temp_tag = collection.clone().store(&db).await?;
-    // now that the collection is stored, we can drop the tags
-    // data is protected by the collection
-    drop(tags);
+    let DataSources {
+        links,
+        dirs,
+        attributes,
+        warnings,
+    } = walk.await??;
+    for warning in &warnings {
+        reporter.warn(warning);
+    }
+    entries.metadata.dirs = dirs.into_iter().collect();
+    entries.metadata.attributes = attributes;
+    // a preserved link is a blob with its target
+    for (name, target) in links {
+        let size = target.len() as u64;
+        let tag = db.import_bytes(target.into(), BlobFormat::Raw).await?;
+        stored.lock().unwrap().insert(*tag.hash());
+        entries.metadata.links.insert(name.clone());
+        let link = ImportedFile {
+            tag,
+            size,
+            original_size: None,
+            source: None,
+            packed: Vec::new(),
+        };
+        entries.add(name, link).await?;
+    }
+    let Entries {
+        collection,
+        metadata,
+        sources,
+        files,
+        size,
+        stored_size,
+    } = entries;
+    let metadata = metadata.store(&db).await?;
+    if let Some(tag) = &metadata {
+        stored.lock().unwrap().insert(*tag.hash());
+    }
+    let last = metadata
+        .as_ref()
+        .map(|tag| (METADATA_NAME.to_string(), *tag.hash()));
+    let temp_tag = collection.store(last).await?;
+    stored.lock().unwrap().insert(*temp_tag.hash());
     show_progress.await??;
-    Ok((temp_tag, size, collection))
+    Ok(Imported {
+        tag: temp_tag,
+        size,
+        compressed_size: options.compress.then_some(stored_size),
+        files,
+        warnings,
+        sources,
+    })
+}
+
+/// A file or link in the database, on its way into a collection.
+struct ImportedFile {
+    /// Keeps the blob until it is added to the collection.
+    tag: TempTag,
+    /// Size of the blob in bytes.
+    size: u64,
+    /// Size of the file in bytes, if the blob is compressed.
+    original_size: Option<u64>,
+    /// The file as it was before it was hashed, if it is referenced.
+    source: Option<Source>,
+    /// The files in the blob, if it is a pack.
+    packed: Vec<PackedFile>,
+}
+
+/// The imported files, on their way into a collection.
+struct Entries<D> {
+    collection: CollectionBuilder<D>,
+    metadata: Metadata,
+    /// The referenced files, which can go stale.
+    sources: Vec<Source>,
+    /// Files in the collection, with each file of a pack counted.
+    files: usize,
+    /// Total size of all files, and of what is actually sent.
+    size: u64,
+    stored_size: u64,
+}
+
+impl<D: iroh_blobs::store::Store> Entries<D> {
+    async fn add(&mut self, name: String, file: ImportedFile) -> anyhow::Result<()> {
+        let hash = *file.tag.hash();
+        self.files += file.packed.len().max(1);
+        self.size += file.original_size.unwrap_or(file.size);
+        self.stored_size += file.size;
+        if !file.packed.is_empty() {
+            self.metadata.packed.insert(name.clone(), file.packed);
+        }
+        match file.original_size {
+            Some(original_size) => {
+                self.metadata.compressed.insert(name.clone(), original_size);
+            }
+            // compressed files are copies, only the referenced ones can go stale
+            None => self.sources.extend(file.source.map(|source| Source {
+                hash: Some(hash),
+                ..source
+            })),
+        }
+        // nothing collects garbage in the database during an import, so the
+        // blob stays without its tag until the collection refers to it
+        self.collection.push(name, hash).await
+    }
+}
+
+/// What the walk of an import found.
+enum Found {
+    File(String, PathBuf),
+    /// Small files that go into one blob, with the name of its entry.
+    Pack(String, Vec<(String, PathBuf)>),
+}
+
+/// Puts small files into packs of about [`PACK_SIZE`] and at most [`PACK_FILES`]
+/// files, in the order they are found.
+#[derive(Default)]
+struct Packer {
+    files: Vec<(String, PathBuf)>,
+    size: u64,
+    packs: usize,
+}
+
+impl Packer {
+    /// Add a file, returning the pack once it is full.
+    fn add(&mut self, name: String, path: PathBuf, size: u64) -> Option<Found> {
+        self.files.push((name, path));
+        self.size += size;
+        if self.size < PACK_SIZE && self.files.len() < PACK_FILES {
+            return None;
+        }
+        self.take()
+    }
+
+    /// Take the files added so far, as a pack unless there is just one.
+    fn take(&mut self) -> Option<Found> {
+        let mut files = std::mem::take(&mut self.files);
+        self.size = 0;
+        if files.len() <= 1 {
+            // a pack of one is only in the way
+            let (name, path) = files.pop()?;
+            return Some(Found::File(name, path));
+        }
+        let name = format!("{PACK_DIR}/{}", self.packs);
+        self.packs += 1;
+        Some(Found::Pack(name, files))
+    }
+}
+
+/// Copy small files into one blob, one after the other.
+async fn import_pack(
+    db: &impl iroh_blobs::store::Store,
+    progress: &AsyncChannelProgressSender<ImportProgress>,
+    name: String,
+    files: Vec<(String, PathBuf)>,
+) -> anyhow::Result<(String, ImportedFile)> {
+    let mut data = Vec::new();
+    let mut packed = Vec::with_capacity(files.len());
+    for (file_name, path) in files {
+        let bytes = tokio::fs::read(&path).await?;
+        packed.push(PackedFile {
+            name: file_name,
+            size: bytes.len() as u64,
+        });
+        data.extend_from_slice(&bytes);
+    }
+    let size = data.len() as u64;
+    let tag = db.import_bytes(data.into(), BlobFormat::Raw).await?;
+    report_done(progress, &name, size, *tag.hash()).await?;
+    let pack = ImportedFile {
+        tag,
+        size,
+        original_size: None,
+        source: None,
+        packed,
+    };
+    Ok((name, pack))
+}
+
+/// Report a blob that was imported without progress of its own, like a file
+/// that was hashed before or a pack, like one that was just hashed.
+async fn report_done(
+    progress: &AsyncChannelProgressSender<ImportProgress>,
+    name: &str,
+    size: u64,
+    hash: Hash,
+) -> anyhow::Result<()> {
+    let id = progress.new_id();
+    let name = name.to_string();
+    progress.send(ImportProgress::Found { id, name }).await?;
+    progress.send(ImportProgress::Size { id, size }).await?;
+    progress
+        .send(ImportProgress::OutboardDone { id, hash })
+        .await?;
+    Ok(())
+}
+
+/// Whether the start of the file at `path` gets smaller when it is compressed.
+///
+/// Deciding from a sample means a file is only read and hashed once, either way.
+async fn compresses(path: &Path) -> anyhow::Result<bool> {
+    let mut sample = Vec::new();
+    tokio::fs::File::open(path)
+        .await?
+        .take(COMPRESS_SAMPLE)
+        .read_to_end(&mut sample)
+        .await?;
+    let mut compressed = Vec::new();
+    ZstdEncoder::new(sample.as_slice())
+        .read_to_end(&mut compressed)
+        .await?;
+    Ok(compressed.len() < sample.len())
+}
+
+/// Import the file at `path` zstd compressed, streaming it through the encoder.
+///
+/// Returns the tag of the compressed blob and its size.
+async fn import_compressed(
+    db: &impl iroh_blobs::store::Store,
+    path: &Path,
+    progress: AsyncChannelProgressSender<ImportProgress>,
+) -> anyhow::Result<(TempTag, u64)> {
+    let file = tokio::fs::File::open(path).await?;
+    let encoder = ZstdEncoder::new(tokio::io::BufReader::new(file));
+    let imported = db
+        .import_stream(ReaderStream::new(encoder), BlobFormat::Raw, progress)
+        .await?;
+    Ok(imported)
+}
+
+/// The number and total size of the files an import of `paths` would include.
+///
+/// Nothing is hashed, so this is cheap enough to show before sharing.
+pub fn preview(paths: Vec<PathBuf>, options: &ImportOptions) -> anyhow::Result<(usize, u64)> {
+    let mut files = 0;
+    let mut size = 0;
+    let data_sources = data_sources(paths, options, |_, path| {
+        files += 1;
+        size += std::fs::metadata(path)?.len();
+        Ok(())
+    })?;
+    for (_, target) in &data_sources.links {
+        size += target.len() as u64;
+    }
+    Ok((files + data_sources.links.len(), size))
+}
+
+/// The entries an import of the shared paths consists of, besides the files.
+#[derive(Debug, Default)]
+struct DataSources {
+    /// (name, target) pairs, one per preserved link.
+    links: Vec<(String, String)>,
+    /// Names of all directories, if metadata is preserved.
+    dirs: Vec<String>,
+    /// Attributes of files and directories, if metadata is preserved.
+    attributes: BTreeMap<String, Attributes>,
+    /// What was left out, for the user.
+    warnings: Vec<String>,
+}
+
+/// Flatten the shared paths into named entries.
+///
+/// Each file is handed to `file` with its name as soon as the walk finds it, so
+/// a caller can start on it right away and doesn't need to keep a list.
+fn data_sources(
+    paths: Vec<PathBuf>,
+    options: &ImportOptions,
+    mut file: impl FnMut(String, PathBuf) -> anyhow::Result<()>,
+) -> anyhow::Result<DataSources> {
+    let mut roots = Vec::new();
+    for path in paths {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("path {} does not exist", path.display()))?;
+        // sharing the same path twice would only add a renamed copy
+        if !roots.iter().any(|(_, root)| *root == path) {
+            let name = path
+                .file_name()
+                .context("can not share a filesystem root")?
+                .to_owned();
+            roots.push((name, path));
+        }
+    }
+    // the metadata entry must stay unique
+    let mut taken = BTreeSet::from([METADATA_NAME.to_string(), PACK_DIR.to_string()]);
+    let mut sources = DataSources::default();
+    let mut skipped_links = Vec::new();
+    for (name, root) in roots {
+        let name = unique_name(&canonical_path_to_string(Path::new(&name), true)?, &taken);
+        taken.insert(name.clone());
+        // the walk also works for files, so we don't need to special case them.
+        // filters never apply to the shared paths themselves.
+        for entry in walk(&root, options)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if is_loop(&e) => {
+                    sources.warnings.push(format!("skipped a link loop: {e}"));
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let path = entry.path();
+            let relative = canonical_path_to_string(path.strip_prefix(&root)?, true)?;
+            let entry_name = if relative.is_empty() {
+                name.clone()
+            } else {
+                format!("{name}/{relative}")
+            };
+            if entry.path_is_symlink() {
+                match options.symlinks {
+                    SymlinkPolicy::Skip => {
+                        skipped_links.push(path.display().to_string());
+                        continue;
+                    }
+                    SymlinkPolicy::Preserve => {
+                        sources.links.push((entry_name, link_target(path)?));
+                        continue;
+                    }
+                    // the walk already resolved the link
+                    SymlinkPolicy::Follow => {}
+                }
+            }
+            let Some(file_type) = entry.file_type() else {
+                continue;
+            };
+            if options.preserve_metadata && (file_type.is_file() || file_type.is_dir()) {
+                let attributes = Attributes::from_metadata(&entry.metadata()?);
+                sources.attributes.insert(entry_name.clone(), attributes);
+                if file_type.is_dir() {
+                    sources.dirs.push(entry_name.clone());
+                }
+            }
+            if file_type.is_file() {
+                // Directories are handled by the walk.
+                file(entry_name, entry.into_path())?;
+            }
+        }
+    }
+    if !skipped_links.is_empty() {
+        sources.warnings.push(format!(
+            "skipped {} symbolic link(s), use --symlinks follow or preserve to share them: {}",
+            skipped_links.len(),
+            skipped_links.join(", ")
+        ));
+    }
+    Ok(sources)
+}
+
+/// The target of the link at `path`, with `/` as separator.
+fn link_target(path: &Path) -> anyhow::Result<String> {
+    let target = std::fs::read_link(path)?;
+    let target = target
+        .to_str()
+        .with_context(|| format!("link {} has a target that is not unicode", path.display()))?;
+    Ok(if cfg!(windows) {
+        target.replace('\\', "/")
+    } else {
+        target.to_string()
+    })
+}
+
+/// Whether following links ran in a circle.
+fn is_loop(error: &ignore::Error) -> bool {
+    match error {
+        ignore::Error::Loop { .. } => true,
+        ignore::Error::WithPath { err, .. }
+        | ignore::Error::WithDepth { err, .. }
+        | ignore::Error::WithLineNumber { err, .. } => is_loop(err),
+        _ => false,
+    }
+}
+
+/// Walk `root`, leaving out what the globs, ignore files and hidden setting exclude.
+///
+/// Links are followed, with loop detection, if the symlink policy says so.
+fn walk(root: &Path, options: &ImportOptions) -> anyhow::Result<ignore::Walk> {
+    let mut overrides = OverrideBuilder::new(root);
+    for glob in &options.include {
+        overrides.add(glob)?;
+    }
+    for glob in &options.exclude {
+        overrides.add(&format!("!{glob}"))?;
+    }
+    let walk = WalkBuilder::new(root)
+        .standard_filters(false)
+        .hidden(options.skip_hidden)
+        .git_ignore(options.gitignore)
+        .git_exclude(options.gitignore)
+        .ignore(options.gitignore)
+        .parents(options.gitignore)
+        .require_git(false)
+        .follow_links(options.symlinks == SymlinkPolicy::Follow)
+        .overrides(overrides.build()?)
+        // the same tree always makes the same packs
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    Ok(walk)
+}
+
+/// `name`, or `name` with the lowest number appended that is not `taken`.
+///
+/// The number goes before the extension, so `a.txt` becomes `a (2).txt`.
+fn unique_name(name: &str, taken: &BTreeSet<String>) -> String {
+    if !taken.contains(name) {
+        return name.to_string();
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name, String::new()),
+    };
+    (2..)
+        .map(|i| format!("{stem} ({i}){extension}"))
+        .find(|candidate| !taken.contains(candidate))
+        .expect("there is always a free number")
+}
+
+/// Import everything read from stdin into the database.
+///
+/// The returned tag refers to a collection with a single blob called `name`.
+pub(crate) async fn import_stdin(
+    name: String,
+    db: impl iroh_blobs::store::Store,
+    reporter: Reporter,
+) -> anyhow::Result<Imported> {
+    let name = canonical_path_to_string(&name, true)?;
+    let (send, recv) = async_channel::bounded(32);
+    let progress = iroh_blobs::util::progress::AsyncChannelProgressSender::new(send);
+    let show_progress = tokio::spawn(show_ingest_progress(recv, reporter));
+    let stream = ReaderStream::new(tokio::io::stdin());
+    let (tag, size) = db.import_stream(stream, BlobFormat::Raw, progress).await?;
+    let collection: Collection = std::iter::once((name, *tag.hash())).collect();
+    let temp_tag = collection.store(&db).await?;
+    // the blob is protected by the collection now
+    drop(tag);
+    show_progress.await??;
+    Ok(Imported {
+        tag: temp_tag,
+        size,
+        compressed_size: None,
+        files: 1,
+        warnings: Vec::new(),
+        sources: Vec::new(),
+    })
 }
 
 pub async fn show_ingest_progress(
     recv: async_channel::Receiver<ImportProgress>,
+    reporter: Reporter,
 ) -> anyhow::Result<()> {
     let mp = MultiProgress::new();
     mp.set_draw_target(ProgressDrawTarget::stderr());
@@ -201,9 +843,29 @@ pub async fn show_ingest_progress(
     );
     // op.set_message(format!("{} Ingesting ...\n", style("[1/2]").bold().dim()));
     // op.set_length(total_files);
+    // only the files that are being hashed, a big import has too many to keep
     let mut names = BTreeMap::new();
     let mut sizes = BTreeMap::new();
+    let mut offsets = BTreeMap::new();
     let mut pbs = BTreeMap::new();
+    let mut files = 0;
+    let mut total_size = 0;
+    // bytes of the files that are done
+    let mut done = 0;
+    let mut current = String::new();
+    let started = Instant::now();
+    let mut last_report = started;
+    let report =
+        |files: usize, size: u64, offsets: &BTreeMap<u64, u64>, done: u64, current: &str| {
+            let hashed = done + offsets.values().sum::<u64>();
+            reporter.emit(TransferEvent::ImportProgress {
+                files,
+                size,
+                hashed,
+                current: current.to_string(),
+                bytes_per_second: (hashed as f64 / started.elapsed().as_secs_f64()) as u64,
+            });
+        };
     loop {
         let event = recv.recv().await;
         match event {
@@ -212,14 +874,19 @@ pub async fn show_ingest_progress(
             }
             Ok(ImportProgress::Size { id, size }) => {
                 sizes.insert(id, size);
-                let total_size = sizes.values().sum::<u64>();
+                files += 1;
+                total_size += size;
                 op.set_message(format!(
                     "{} Ingesting {} files, {}\n",
                     style("[1/2]").bold().dim(),
-                    sizes.len(),
+                    files,
                     HumanBytes(total_size)
                 ));
                 let name = names.get(&id).cloned().unwrap_or_default();
+                current.clone_from(&name);
+                if due(&mut last_report) {
+                    report(files, total_size, &offsets, done, &current);
+                }
                 let pb = mp.add(ProgressBar::hidden());
                 pb.set_style(ProgressStyle::with_template(
                     "{msg}{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}",
@@ -232,12 +899,25 @@ pub async fn show_ingest_progress(
                 if let Some(pb) = pbs.get(&id) {
                     pb.set_position(offset);
                 }
+                offsets.insert(id, offset);
+                if due(&mut last_report) {
+                    if let Some(name) = names.get(&id) {
+                        current.clone_from(name);
+                    }
+                    report(files, total_size, &offsets, done, &current);
+                }
             }
             Ok(ImportProgress::OutboardDone { id, .. }) => {
                 // you are not guaranteed to get any OutboardProgress
                 if let Some(pb) = pbs.remove(&id) {
                     pb.finish_and_clear();
                 }
+                offsets.remove(&id);
+                names.remove(&id);
+                done += sizes.remove(&id).unwrap_or_default();
+                if due(&mut last_report) {
+                    report(files, total_size, &offsets, done, &current);
+                }
             }
             Ok(ImportProgress::CopyProgress { .. }) => {
                 // we are not copying anything
@@ -248,10 +928,25 @@ pub async fn show_ingest_progress(
             }
         }
     }
+    // the last state is never skipped
+    report(files, total_size, &offsets, done, &current);
     op.finish_and_clear();
     Ok(())
 }
 
+/// Whether it is time for the next progress report, then starting the wait for
+/// the one after.
+///
+/// Large files and many small ones report often, this keeps them from flooding
+/// the view and stdout.
+fn due(last_report: &mut Instant) -> bool {
+    if last_report.elapsed() < IMPORT_REPORT {
+        return false;
+    }
+    *last_report = Instant::now();
+    true
+}
+
 /// This function converts an already canonical path to a string.
 ///
 /// If `must_be_relative` is true, the function will fail if any component of the path is
@@ -0,0 +1,848 @@
+use crate::backend::cache::HashCache;
+use crate::backend::metadata::read_blob;
+use crate::backend::report::Reporter;
+use crate::backend::scratch::ScratchDir;
+use crate::backend::send::{import, import_stdin, preview, Source};
+use crate::backend::upload::{PeerUpload, Served, TrackedBlobs, UploadTracker};
+use crate::backend::{apply_options, create_data_dir, ensure_space, identity};
+use crate::interconnect::{
+    display_from_str, AccessOptions, AddrInfoOptions, Approval, CommonArgs, ExpiryOptions,
+    ImportModeOption, ImportOptions,
+};
+use anyhow::Context;
+use data_encoding::HEXLOWER;
+use fs2::FileExt;
+use iroh::{discovery::pkarr::PkarrPublisher, protocol::Router, Endpoint, NodeId, SecretKey};
+use iroh_blobs::{
+    hashseq::HashSeq,
+    net_protocol::Blobs,
+    store::{fs::Store, EntryStatus, MapMut, ReadableStore, Store as _},
+    ticket::BlobTicket,
+    BlobFormat, Hash, HashAndFormat, TempTag,
+};
+use rand::random;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub type ShareId = u64;
+
+/// How often shares are checked for expiry.
+const EXPIRY_CHECK: Duration = Duration::from_secs(1);
+
+/// How often referenced files are checked for changes.
+const SOURCE_CHECK: Duration = Duration::from_secs(5);
+
+/// A share served by a [`ShareManager`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareInfo {
+    pub id: ShareId,
+    /// The shared files and directories, "-" for stdin.
+    pub paths: Vec<PathBuf>,
+    #[serde(with = "display_from_str")]
+    pub ticket: BlobTicket,
+    #[serde(with = "display_from_str")]
+    pub hash: Hash,
+    /// Total size of all files in bytes.
+    pub size: u64,
+    /// Size of what peers download in bytes, if files are compressed.
+    #[serde(default)]
+    pub compressed_size: Option<u64>,
+    pub files: usize,
+    /// When the share was added, in seconds since the unix epoch.
+    pub started: u64,
+    /// Bytes sent to all peers so far.
+    pub uploaded: u64,
+    /// The peers that requested the share, in the order they first connected.
+    pub peers: Vec<PeerUpload>,
+    /// What was left out of the share, like skipped symbolic links.
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    /// The only nodes that may download the share, anyone if empty.
+    #[serde(default)]
+    pub allowed: Vec<NodeId>,
+    /// Whether each new peer waits for the sender's approval.
+    #[serde(default)]
+    pub ask: bool,
+    /// When the share stops by itself.
+    #[serde(default)]
+    pub expiry: ExpiryOptions,
+    /// Downloads that completed so far.
+    #[serde(default)]
+    pub downloads: u64,
+    /// When the share will stop because of its time limit or idle timeout, in
+    /// seconds since the unix epoch.
+    #[serde(default)]
+    pub expires: Option<u64>,
+    /// Referenced files that changed on disk since the share started, and are
+    /// no longer served.
+    #[serde(default)]
+    pub stale: Vec<PathBuf>,
+}
+
+impl ShareInfo {
+    /// Whether the share reached a limit of its [`ExpiryOptions`] at `now`, in
+    /// seconds since the unix epoch.
+    pub fn is_expired(&self, now: u64) -> bool {
+        let downloaded = self
+            .expiry
+            .max_downloads
+            .is_some_and(|max| self.downloads >= max);
+        downloaded || self.expires.is_some_and(|expires| now >= expires)
+    }
+}
+
+/// Serves any number of shares from one endpoint and one store.
+///
+/// A manager made with [`ShareManager::spawn`] uses the store of the hash cache,
+/// which keeps the blobs of referenced files for the next run, and deletes the
+/// rest on [`ShareManager::shutdown`]. If another transfer uses the cache, it
+/// keeps its store in a `scratch/send-*` directory of the data directory instead,
+/// which is removed on shutdown.
+/// One made with [`ShareManager::persistent`] keeps the store and a registry of
+/// its shares in the data directory, and serves them again on the next start.
+/// Only one process at a time serves them, it holds the lock file meanwhile.
+#[derive(Debug, Clone)]
+pub struct ShareManager {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    router: Router,
+    blobs: Blobs<Store>,
+    blobs_data_dir: PathBuf,
+    /// The temporary store, removed on shutdown.
+    scratch: Option<ScratchDir>,
+    /// The files the store has blobs for, which are not hashed again.
+    cache: Option<HashCache>,
+    /// Where the shares are recorded, if they outlive the manager.
+    registry: Option<Registry>,
+    /// The registry as last written, to only write it when it changes.
+    saved: Mutex<Vec<u8>>,
+    uploads: UploadTracker,
+    ticket_type: AddrInfoOptions,
+    next_id: AtomicU64,
+    shares: Mutex<BTreeMap<ShareId, Share>>,
+}
+
+/// The registry of a persistent [`ShareManager`], locked until it is dropped.
+#[derive(Debug)]
+struct Registry {
+    path: PathBuf,
+    _lock: File,
+}
+
+#[derive(Debug)]
+struct Share {
+    info: ShareInfo,
+    /// The collection and all of its blobs, deleted from the store once the share stops.
+    hashes: BTreeSet<Hash>,
+    /// The blobs of the files, that a peer must get for a complete download.
+    files: BTreeSet<Hash>,
+    access: AccessOptions,
+    /// The referenced files as they were imported.
+    sources: Vec<Source>,
+    /// Protects the collection from garbage collection while it is shared.
+    _tag: TempTag,
+}
+
+/// A share as recorded in the registry of a persistent [`ShareManager`].
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    info: ShareInfo,
+    hashes: BTreeSet<Hash>,
+    access: AccessOptions,
+    sources: Vec<Source>,
+}
+
+impl ShareManager {
+    /// Bind an endpoint and start serving a store without shares, that only
+    /// keeps what it has cached.
+    pub async fn spawn(common: &CommonArgs, ticket_type: AddrInfoOptions) -> anyhow::Result<Self> {
+        let secret_key = identity::secret_key(common.identity.as_deref())?;
+        let manager = match HashCache::open(common).await? {
+            Some(cache) => {
+                let blobs_data_dir = cache.store_dir().to_owned();
+                let manager = Self::start(
+                    common,
+                    ticket_type,
+                    secret_key,
+                    blobs_data_dir,
+                    None,
+                    Some(cache),
+                    None,
+                )
+                .await?;
+                manager.prune_cache().await?;
+                manager
+            }
+            None => {
+                // another transfer has the cache, so everything is hashed
+                let suffix = random::<[u8; 16]>();
+                let name = format!("send-{}", HEXLOWER.encode(&suffix));
+                let scratch = ScratchDir::create(common, &name).await?;
+                let blobs_data_dir = scratch.path().to_owned();
+                Self::start(
+                    common,
+                    ticket_type,
+                    secret_key,
+                    blobs_data_dir,
+                    Some(scratch),
+                    None,
+                    None,
+                )
+                .await?
+            }
+        };
+        manager.watch();
+        Ok(manager)
+    }
+
+    /// Bind an endpoint and serve the shares recorded for this identity, which
+    /// then outlive the manager until they are stopped.
+    ///
+    /// Referenced files that changed since they were imported are no longer
+    /// served, the returned warnings say which.
+    pub async fn persistent(
+        common: &CommonArgs,
+        ticket_type: AddrInfoOptions,
+    ) -> anyhow::Result<(Self, Vec<String>)> {
+        let secret_key = identity::secret_key(common.identity.as_deref())?;
+        // the tickets of a store only work with the node id they were made for
+        let dir = Path::new("shares").join(secret_key.public().to_string());
+        let store = create_data_dir(common, &dir.join("store")).await?;
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(store.with_file_name("lock"))?;
+        anyhow::ensure!(
+            lock.try_lock_exclusive().is_ok(),
+            "the shares of node {} are served by another sendme process",
+            secret_key.public()
+        );
+        let registry = Registry {
+            path: store.with_file_name("shares.json"),
+            _lock: lock,
+        };
+        let manager = Self::start(
+            common,
+            ticket_type,
+            secret_key,
+            store,
+            None,
+            None,
+            Some(registry),
+        )
+        .await?;
+        let warnings = manager.restore().await?;
+        // only now, the watchers write the registry
+        manager.watch();
+        Ok((manager, warnings))
+    }
+
+    async fn start(
+        common: &CommonArgs,
+        ticket_type: AddrInfoOptions,
+        secret_key: SecretKey,
+        blobs_data_dir: PathBuf,
+        scratch: Option<ScratchDir>,
+        cache: Option<HashCache>,
+        registry: Option<Registry>,
+    ) -> anyhow::Result<Self> {
+        // create a magicsocket endpoint
+        let mut builder = Endpoint::builder()
+            .alpns(vec![iroh_blobs::protocol::ALPN.to_vec()])
+            .secret_key(secret_key)
+            .relay_mode(common.relay.clone().into());
+        if ticket_type == AddrInfoOptions::Id {
+            builder = builder
+                .add_discovery(|secret_key| Some(PkarrPublisher::n0_dns(secret_key.clone())));
+        }
+        if let Some(addr) = common.magic_ipv4_addr {
+            builder = builder.bind_addr_v4(addr);
+        }
+        if let Some(addr) = common.magic_ipv6_addr {
+            builder = builder.bind_addr_v6(addr);
+        }
+
+        let endpoint = builder.bind().await?;
+        let uploads = UploadTracker::default();
+        let blobs = Blobs::persistent(&blobs_data_dir).await?.build(&endpoint);
+
+        let router = Router::builder(endpoint)
+            .accept(
+                iroh_blobs::ALPN,
+                TrackedBlobs::new(blobs.clone(), uploads.clone()),
+            )
+            .spawn()
+            .await?;
+
+        let inner = Arc::new(Inner {
+            router,
+            blobs,
+            blobs_data_dir,
+            scratch,
+            cache,
+            registry,
+            saved: Mutex::new(Vec::new()),
+            uploads,
+            ticket_type,
+            next_id: AtomicU64::new(0),
+            shares: Mutex::new(BTreeMap::new()),
+        });
+        Ok(Self { inner })
+    }
+
+    /// Import files and directories, or stdin if `paths` is just "-", and start
+    /// serving them in one collection to the peers `access` allows, until
+    /// `expiry` says otherwise.
+    pub async fn add(
+        &self,
+        paths: Vec<PathBuf>,
+        name: String,
+        options: &ImportOptions,
+        access: &AccessOptions,
+        expiry: &ExpiryOptions,
+        reporter: Reporter,
+    ) -> anyhow::Result<ShareInfo> {
+        let store = self.inner.blobs.store().clone();
+        let stdin = paths.iter().any(|path| path == Path::new("-"));
+        anyhow::ensure!(!paths.is_empty(), "nothing to share");
+        anyhow::ensure!(
+            !stdin || paths.len() == 1,
+            "stdin can not be shared together with other paths"
+        );
+        let copied =
+            options.compress || options.pack || options.import_mode == ImportModeOption::Snapshot;
+        if copied && !stdin {
+            // copied files need room in the store, referenced ones hardly any
+            let (_, size) = preview(paths.clone(), options)?;
+            ensure_space(&self.inner.blobs_data_dir, size)?;
+        }
+        // dropping this future cancels the import, and like a failed import
+        // leaves nothing behind
+        let mut cleanup = Cleanup {
+            manager: self.clone(),
+            stored: Default::default(),
+            armed: true,
+        };
+        let imported = if stdin {
+            import_stdin(name, store, reporter).await?
+        } else {
+            let cache = self.inner.cache.as_ref();
+            import(
+                paths.clone(),
+                options,
+                store,
+                reporter,
+                cache,
+                &cleanup.stored,
+            )
+            .await?
+        };
+        let hash = *imported.tag.hash();
+        let ticket = self.ticket(hash).await?;
+
+        let info = ShareInfo {
+            id: self.inner.next_id.fetch_add(1, Ordering::Relaxed),
+            paths,
+            ticket,
+            hash,
+            size: imported.size,
+            compressed_size: imported.compressed_size,
+            files: imported.files,
+            started: unix_secs(SystemTime::now()),
+            uploaded: 0,
+            peers: Vec::new(),
+            warnings: imported.warnings,
+            allowed: access.allow.clone(),
+            ask: access.ask,
+            expiry: expiry.clone(),
+            downloads: 0,
+            expires: None,
+            stale: Vec::new(),
+        };
+        // the hash seq starts with the metadata blob of the collection, before the files
+        let hash_seq = HashSeq::try_from(read_blob(self.inner.blobs.store(), &hash).await?)?;
+        let hashes = std::iter::once(hash).chain(hash_seq.iter()).collect();
+        let files = hash_seq.iter().skip(1).collect();
+        let mut shares = self.inner.shares.lock().unwrap();
+        shares.insert(
+            info.id,
+            Share {
+                info: info.clone(),
+                hashes,
+                files,
+                access: access.clone(),
+                sources: imported.sources,
+                _tag: imported.tag,
+            },
+        );
+        cleanup.armed = false;
+        self.restrict(&shares);
+        self.save(&shares)?;
+        if let Some(cache) = &self.inner.cache {
+            cache.save()?;
+        }
+        Ok(self.info(&shares[&info.id]))
+    }
+
+    /// Delete blobs from the store, except the ones a share contains or the
+    /// cache keeps.
+    async fn delete_unused(&self, mut hashes: BTreeSet<Hash>) -> anyhow::Result<()> {
+        {
+            let shares = self.inner.shares.lock().unwrap();
+            for share in shares.values() {
+                hashes.retain(|hash| !share.hashes.contains(hash));
+            }
+        }
+        if let Some(cache) = &self.inner.cache {
+            let cached = cache.hashes();
+            hashes.retain(|hash| !cached.contains(hash));
+        }
+        self.inner
+            .blobs
+            .store()
+            .delete(hashes.into_iter().collect())
+            .await?;
+        Ok(())
+    }
+
+    /// A ticket for the collection `hash`, with the current address of the endpoint.
+    async fn ticket(&self, hash: Hash) -> anyhow::Result<BlobTicket> {
+        // wait for the endpoint to figure out its address before making a ticket
+        let endpoint = self.inner.router.endpoint();
+        let _ = endpoint.home_relay().initialized().await?;
+        let mut addr = endpoint.node_addr().await?;
+        apply_options(&mut addr, self.inner.ticket_type);
+        Ok(BlobTicket::new(addr, hash, BlobFormat::HashSeq)?)
+    }
+
+    /// Forget the cached files that changed since the last run, and delete all
+    /// blobs the cache doesn't need, like the collections of earlier shares.
+    ///
+    /// Only called before anything is shared.
+    async fn prune_cache(&self) -> anyhow::Result<()> {
+        let Some(cache) = &self.inner.cache else {
+            return Ok(());
+        };
+        cache.prune();
+        let cached = cache.hashes();
+        let store = self.inner.blobs.store();
+        let unused = store
+            .blobs()
+            .await?
+            .filter_map(Result::ok)
+            .filter(|hash| !cached.contains(hash))
+            .collect();
+        store.delete(unused).await?;
+        cache.save()?;
+        Ok(())
+    }
+
+    /// Serve the shares in the registry again.
+    ///
+    /// They keep their ids and limits, but get a fresh ticket, since the
+    /// addresses of the endpoint may have changed. The old tickets keep working
+    /// as long as they find the node. Files that changed in the meantime go
+    /// stale like in a running share.
+    async fn restore(&self) -> anyhow::Result<Vec<String>> {
+        let Some(Registry { path: registry, .. }) = &self.inner.registry else {
+            return Ok(Vec::new());
+        };
+        let records: Vec<Record> = match std::fs::read(registry) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("invalid share registry {}", registry.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let store = self.inner.blobs.store();
+        let mut warnings = Vec::new();
+        let mut restored = Vec::new();
+        for record in records {
+            let paths = record
+                .info
+                .paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            // stale blobs are gone from the store on purpose
+            let stale = record
+                .sources
+                .iter()
+                .filter(|source| record.info.stale.contains(&source.path))
+                .filter_map(|source| source.hash)
+                .collect::<BTreeSet<_>>();
+            let mut complete = true;
+            for hash in record.hashes.difference(&stale) {
+                complete &= matches!(store.entry_status(hash).await?, EntryStatus::Complete);
+            }
+            if !complete {
+                warnings.push(format!("not sharing {paths} again, its data is missing"));
+                continue;
+            }
+            let ticket = self.ticket(record.info.hash).await?;
+            let hash_seq = HashSeq::try_from(read_blob(store, &record.info.hash).await?)?;
+            let files = hash_seq.iter().skip(1).collect();
+            restored.push((record, ticket, files));
+        }
+        let mut shares = self.inner.shares.lock().unwrap();
+        for (record, ticket, files) in restored {
+            let tag = store.temp_tag(HashAndFormat::hash_seq(record.info.hash));
+            let info = ShareInfo {
+                ticket,
+                ..record.info
+            };
+            self.inner.next_id.fetch_max(info.id + 1, Ordering::Relaxed);
+            shares.insert(
+                info.id,
+                Share {
+                    info,
+                    hashes: record.hashes,
+                    files,
+                    access: record.access,
+                    sources: record.sources,
+                    _tag: tag,
+                },
+            );
+        }
+        self.restrict(&shares);
+        // forget the shares that were not restored
+        self.save(&shares)?;
+        drop(shares);
+        for (_, path) in self.drop_stale().await? {
+            warnings.push(format!(
+                "{} changed since it was shared and is no longer served",
+                path.display()
+            ));
+        }
+        Ok(warnings)
+    }
+
+    /// Stop serving the referenced files that changed on disk, returning them
+    /// with their share.
+    ///
+    /// Their blobs are deleted from the store, which leaves the files alone, so
+    /// receivers get a clean "not found" instead of data that stops matching
+    /// its hash halfway through. Blobs that another unchanged file still shares
+    /// are kept.
+    async fn drop_stale(&self) -> anyhow::Result<Vec<(ShareId, PathBuf)>> {
+        let sources = {
+            let shares = self.inner.shares.lock().unwrap();
+            shares
+                .values()
+                .flat_map(|share| {
+                    share
+                        .sources
+                        .iter()
+                        .filter(|source| !share.info.stale.contains(&source.path))
+                        .map(|source| (share.info.id, source.clone()))
+                })
+                .collect::<Vec<_>>()
+        };
+        let changed = tokio::task::spawn_blocking(move || {
+            sources
+                .into_iter()
+                .filter(|(_, source)| !source.is_unchanged())
+                .collect::<Vec<_>>()
+        })
+        .await?;
+        if changed.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut hashes = BTreeSet::new();
+        let mut stale = Vec::new();
+        {
+            let mut shares = self.inner.shares.lock().unwrap();
+            for (id, source) in changed {
+                // it may have been stopped in the meantime
+                if let Some(share) = shares.get_mut(&id) {
+                    share.info.stale.push(source.path.clone());
+                    hashes.extend(source.hash);
+                    stale.push((id, source.path));
+                }
+            }
+            // the same content may still be shared from a file that did not change
+            for share in shares.values() {
+                for source in &share.sources {
+                    if !share.info.stale.contains(&source.path) {
+                        if let Some(hash) = &source.hash {
+                            hashes.remove(hash);
+                        }
+                    }
+                }
+            }
+            self.save(&shares)?;
+        }
+        // the blobs no longer match the files
+        if let Some(cache) = &self.inner.cache {
+            cache.forget(&hashes);
+            cache.save()?;
+        }
+        self.inner
+            .blobs
+            .store()
+            .delete(hashes.into_iter().collect())
+            .await?;
+        Ok(stale)
+    }
+
+    /// Start the tasks that stop expired shares and stale files, until the
+    /// manager is dropped.
+    fn watch(&self) {
+        tokio::spawn(expire_shares(Arc::downgrade(&self.inner)));
+        tokio::spawn(drop_stale_sources(Arc::downgrade(&self.inner)));
+    }
+
+    /// Write the registry, if the shares outlive the manager and changed.
+    fn save(&self, shares: &BTreeMap<ShareId, Share>) -> anyhow::Result<()> {
+        let Some(Registry { path: registry, .. }) = &self.inner.registry else {
+            return Ok(());
+        };
+        let records = shares
+            .values()
+            .map(|share| Record {
+                // only what lasts, peers connect anew
+                info: ShareInfo {
+                    uploaded: 0,
+                    peers: Vec::new(),
+                    expires: None,
+                    ..self.info(share)
+                },
+                hashes: share.hashes.clone(),
+                access: share.access.clone(),
+                sources: share.sources.clone(),
+            })
+            .collect::<Vec<_>>();
+        let data = serde_json::to_vec_pretty(&records)?;
+        let mut saved = self.inner.saved.lock().unwrap();
+        if *saved == data {
+            return Ok(());
+        }
+        let temp = registry.with_extension("json.tmp");
+        std::fs::write(&temp, &data)?;
+        std::fs::rename(temp, registry)?;
+        *saved = data;
+        Ok(())
+    }
+
+    /// All running shares, oldest first.
+    pub fn list(&self) -> Vec<ShareInfo> {
+        let shares = self.inner.shares.lock().unwrap();
+        shares.values().map(|share| self.info(share)).collect()
+    }
+
+    pub fn get(&self, id: ShareId) -> Option<ShareInfo> {
+        let shares = self.inner.shares.lock().unwrap();
+        shares.get(&id).map(|share| self.info(share))
+    }
+
+    fn info(&self, share: &Share) -> ShareInfo {
+        let uploads = &self.inner.uploads;
+        let hash = &share.info.hash;
+        let peers = uploads.peers(hash);
+        let started = share.info.started;
+        let last_active = uploads.last_active(hash).map_or(started, unix_secs);
+        let expiry = &share.info.expiry;
+        let expires = [
+            expiry.expire_after.map(|after| started + after.as_secs()),
+            expiry
+                .idle_timeout
+                .map(|timeout| last_active + timeout.as_secs()),
+        ];
+        ShareInfo {
+            uploaded: peers.iter().map(|peer| peer.bytes).sum(),
+            peers,
+            // a restored share counts on from where it stopped
+            downloads: share.info.downloads + uploads.downloads(hash),
+            expires: expires.into_iter().flatten().min(),
+            ..share.info.clone()
+        }
+    }
+
+    /// The shares that reached a limit and should stop.
+    fn expired(&self) -> Vec<ShareId> {
+        let now = unix_secs(SystemTime::now());
+        let shares = self.inner.shares.lock().unwrap();
+        shares
+            .values()
+            .map(|share| self.info(share))
+            .filter(|info| info.is_expired(now))
+            .map(|info| info.id)
+            .collect()
+    }
+
+    /// Stop serving a share.
+    ///
+    /// Its data is deleted from the store, unless another share contains it, so
+    /// the ticket stops working right away.
+    pub async fn stop(&self, id: ShareId) -> anyhow::Result<()> {
+        let mut unused = {
+            let mut shares = self.inner.shares.lock().unwrap();
+            let share = shares
+                .remove(&id)
+                .ok_or_else(|| anyhow::anyhow!("no share with id {id}"))?;
+            let mut unused = share.hashes;
+            for other in shares.values() {
+                unused.retain(|hash| !other.hashes.contains(hash));
+            }
+            if unused.contains(&share.info.hash) {
+                self.inner.uploads.remove(&share.info.hash);
+            }
+            self.restrict(&shares);
+            self.save(&shares)?;
+            unused
+        };
+        if let Some(cache) = &self.inner.cache {
+            let cached = cache.hashes();
+            unused.retain(|hash| !cached.contains(hash));
+        }
+        self.inner
+            .blobs
+            .store()
+            .delete(unused.into_iter().collect())
+            .await?;
+        Ok(())
+    }
+
+    /// Allow or deny a peer that waits for approval to download a share.
+    pub fn decide(&self, id: ShareId, node_id: NodeId, approval: Approval) -> anyhow::Result<()> {
+        let root = self
+            .get(id)
+            .ok_or_else(|| anyhow::anyhow!("no share with id {id}"))?
+            .hash;
+        anyhow::ensure!(
+            self.inner.uploads.decide(&root, node_id, approval),
+            "{node_id} is not waiting for share {id}"
+        );
+        Ok(())
+    }
+
+    /// Tell the upload tracker which shares are running and how they are restricted.
+    ///
+    /// A hash is open to anyone if any share with it is, since they all serve
+    /// the same data.
+    fn restrict(&self, shares: &BTreeMap<ShareId, Share>) {
+        let served = shares
+            .values()
+            .map(|share| {
+                // stale files can't be downloaded anymore
+                let stale = share
+                    .sources
+                    .iter()
+                    .filter(|source| share.info.stale.contains(&source.path))
+                    .filter_map(|source| source.hash)
+                    .collect::<BTreeSet<_>>();
+                Served {
+                    root: share.info.hash,
+                    hashes: share.hashes.clone(),
+                    files: share.files.difference(&stale).copied().collect(),
+                    access: share.access.clone(),
+                }
+            })
+            .collect();
+        self.inner.uploads.restrict(served);
+    }
+
+    /// The endpoint all shares are served from.
+    pub fn endpoint(&self) -> &Endpoint {
+        self.inner.router.endpoint()
+    }
+
+    /// Shut down the endpoint.
+    ///
+    /// A temporary store is removed with all shares, a persistent one keeps
+    /// them for the next start. The store of the cache only keeps the blobs of
+    /// the cached files.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        if self.inner.registry.is_none() {
+            let shares = std::mem::take(&mut *self.inner.shares.lock().unwrap());
+            if let Some(cache) = &self.inner.cache {
+                let hashes = shares
+                    .into_values()
+                    .flat_map(|share| share.hashes)
+                    .collect();
+                self.delete_unused(hashes).await?;
+                cache.save()?;
+            }
+        } else {
+            self.save(&self.inner.shares.lock().unwrap())?;
+        }
+        let router = self.inner.router.clone();
+        tokio::time::timeout(Duration::from_secs(2), router.shutdown()).await??;
+        if let Some(scratch) = &self.inner.scratch {
+            scratch.remove().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Deletes what an import stored unless it is disarmed, once the import is done.
+struct Cleanup {
+    manager: ShareManager,
+    stored: Mutex<BTreeSet<Hash>>,
+    armed: bool,
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        let stored = std::mem::take(self.stored.get_mut().unwrap());
+        if !self.armed || stored.is_empty() {
+            return;
+        }
+        // a dropped future can't wait, so this happens in the background
+        let manager = self.manager.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move { manager.delete_unused(stored).await.ok() });
+        }
+    }
+}
+
+/// Stop shares once they expire, until the manager is dropped.
+async fn expire_shares(inner: Weak<Inner>) {
+    let mut check = tokio::time::interval(EXPIRY_CHECK);
+    loop {
+        check.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            break;
+        };
+        let manager = ShareManager { inner };
+        for id in manager.expired() {
+            // it may have been stopped by hand in the meantime
+            manager.stop(id).await.ok();
+        }
+        // keep the download counts of persistent shares
+        manager.save(&manager.inner.shares.lock().unwrap()).ok();
+    }
+}
+
+/// Stop serving files that change, until the manager is dropped.
+async fn drop_stale_sources(inner: Weak<Inner>) {
+    let mut check = tokio::time::interval(SOURCE_CHECK);
+    loop {
+        check.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            break;
+        };
+        let manager = ShareManager { inner };
+        // the shares say which files went stale
+        manager.drop_stale().await.ok();
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,488 @@
+use crate::interconnect::{display_from_str, AccessOptions, Approval};
+use iroh::{
+    endpoint::{get_remote_node_id, Connecting, Connection},
+    protocol::ProtocolHandler,
+    NodeId,
+};
+use iroh_blobs::{
+    net_protocol::Blobs,
+    provider::{self, CustomEventSender, Event, EventSender},
+    store::fs::Store,
+    Hash,
+};
+use n0_future::boxed::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::sync::oneshot;
+
+/// The provider sends blobs in groups of this many bytes.
+const CHUNK_GROUP_SIZE: u64 = 16 * 1024;
+
+/// What one peer downloaded of a share.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerUpload {
+    #[serde(with = "display_from_str")]
+    pub node_id: NodeId,
+    /// Blobs sent completely, including the collection itself.
+    pub blobs: u64,
+    /// Bytes sent, including blobs still in flight.
+    pub bytes: u64,
+    pub state: UploadState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadState {
+    Transferring,
+    Done,
+    Aborted,
+    /// The peer is not allowed to download the share.
+    Refused,
+    /// The peer waits for the sender to allow or deny the download.
+    Pending,
+}
+
+/// Tracks the uploads of each peer, from the events of the blobs provider.
+///
+/// It also enforces access restrictions, closing the connection of a peer that
+/// requests a restricted hash or one that no share contains before anything is
+/// sent, or holding the request until the sender approves it.
+#[derive(Debug, Clone, Default)]
+pub struct UploadTracker {
+    inner: Arc<Mutex<Uploads>>,
+}
+
+#[derive(Debug, Default)]
+struct Uploads {
+    /// The node and connection of each open connection, by connection id.
+    nodes: HashMap<u64, (NodeId, Connection)>,
+    /// The root of each share that contains a hash, and how the share restricts
+    /// access. Other hashes are refused, even if they are still in the store.
+    shared: HashMap<Hash, Vec<(Hash, AccessOptions)>>,
+    /// Nodes the sender always allows when asked, by the root they were allowed for.
+    trusted: HashSet<(Hash, NodeId)>,
+    /// Connections the sender allowed once.
+    approved: HashSet<u64>,
+    /// Held requests by share root and node, with their connection id. Requests
+    /// of single blobs wait under the share they were counted for.
+    waiting: HashMap<(Hash, NodeId), Vec<(u64, oneshot::Sender<Approval>)>>,
+    /// Requests in flight by connection and request id.
+    requests: HashMap<(u64, u64), Request>,
+    /// Peers by the root of the share they requested from, in the order they
+    /// first requested it.
+    peers: HashMap<Hash, Vec<PeerUpload>>,
+    /// The file blobs of each share by root, all of which a peer must get for a
+    /// complete download.
+    files: HashMap<Hash, BTreeSet<Hash>>,
+    /// Bytes of each file blob sent to a node for a share, until it got all of them.
+    sent: HashMap<(Hash, NodeId), Sent>,
+    /// Completed downloads by root hash, one for each time a node got all files.
+    downloads: HashMap<Hash, u64>,
+    /// When anything last happened with each root hash.
+    active: HashMap<Hash, SystemTime>,
+}
+
+/// What a node got of the files of a share so far.
+#[derive(Debug, Default)]
+struct Sent {
+    bytes: HashMap<Hash, u64>,
+    /// Files sent completely.
+    files: BTreeSet<Hash>,
+}
+
+/// A running share, as far as uploads are concerned.
+#[derive(Debug, Clone)]
+pub(crate) struct Served {
+    pub root: Hash,
+    /// All blobs of the collection, the root and the metadata blob included.
+    pub hashes: BTreeSet<Hash>,
+    /// The blobs of the files that can still be downloaded.
+    pub files: BTreeSet<Hash>,
+    pub access: AccessOptions,
+}
+
+/// What happens to a request, with the root of the share it is counted for.
+enum Verdict {
+    Serve(Hash),
+    Ask(Hash),
+    /// The root is `None` if no share contains the hash.
+    Refuse(Option<Hash>),
+}
+
+#[derive(Debug)]
+struct Request {
+    /// The root of the share, also for requests of a single blob of it.
+    root: Hash,
+    node_id: NodeId,
+    /// End offset of the blob that is being sent, already counted.
+    offset: u64,
+}
+
+impl UploadTracker {
+    /// The peers that requested the collection `root`.
+    pub fn peers(&self, root: &Hash) -> Vec<PeerUpload> {
+        let uploads = self.inner.lock().unwrap();
+        uploads.peers.get(root).cloned().unwrap_or_default()
+    }
+
+    /// How many downloads of the collection `root` completed.
+    pub fn downloads(&self, root: &Hash) -> u64 {
+        let uploads = self.inner.lock().unwrap();
+        uploads.downloads.get(root).copied().unwrap_or_default()
+    }
+
+    /// When a peer last requested or downloaded the collection `root`.
+    pub fn last_active(&self, root: &Hash) -> Option<SystemTime> {
+        self.inner.lock().unwrap().active.get(root).copied()
+    }
+
+    /// Forget the peers of a collection that is no longer shared.
+    pub fn remove(&self, root: &Hash) {
+        let mut uploads = self.inner.lock().unwrap();
+        uploads.peers.remove(root);
+        uploads.downloads.remove(root);
+        uploads.files.remove(root);
+        uploads.sent.retain(|(hash, _), _| hash != root);
+        uploads.active.remove(root);
+        uploads.waiting.retain(|(hash, _), _| hash != root);
+        uploads.trusted.retain(|(hash, _)| hash != root);
+    }
+
+    /// Replace the running shares.
+    ///
+    /// Hashes that none of them contains are refused to anyone.
+    pub(crate) fn restrict(&self, served: Vec<Served>) {
+        let mut shared = HashMap::<Hash, Vec<(Hash, AccessOptions)>>::new();
+        let mut files = HashMap::new();
+        for share in served {
+            for hash in share.hashes {
+                shared
+                    .entry(hash)
+                    .or_default()
+                    .push((share.root, share.access.clone()));
+            }
+            files.insert(share.root, share.files);
+        }
+        let mut uploads = self.inner.lock().unwrap();
+        uploads.shared = shared;
+        uploads.files = files;
+    }
+
+    /// Answer the held requests of `node_id` for `root`.
+    ///
+    /// Returns false if the node is not waiting.
+    pub(crate) fn decide(&self, root: &Hash, node_id: NodeId, approval: Approval) -> bool {
+        let mut uploads = self.inner.lock().unwrap();
+        let Some(waiting) = uploads.waiting.remove(&(*root, node_id)) else {
+            return false;
+        };
+        if approval == Approval::Always {
+            uploads.trusted.insert((*root, node_id));
+        }
+        for (_, sender) in waiting {
+            sender.send(approval).ok();
+        }
+        true
+    }
+
+    /// Serve, refuse or hold a new request, depending on the restrictions.
+    async fn request_received(&self, connection_id: u64, request_id: u64, hash: Hash) {
+        let (answer, root, node_id, connection) = {
+            let mut uploads = self.inner.lock().unwrap();
+            let Some((node_id, connection)) = uploads.nodes.get(&connection_id).cloned() else {
+                return;
+            };
+            let root = match uploads.verdict(&hash, node_id, connection_id) {
+                Verdict::Serve(root) => {
+                    return uploads.start(connection_id, request_id, root, node_id)
+                }
+                Verdict::Refuse(root) => return uploads.refuse(root, node_id, &connection),
+                Verdict::Ask(root) => root,
+            };
+            uploads.active.insert(root, SystemTime::now());
+            let (sender, answer) = oneshot::channel();
+            let waiting = uploads.waiting.entry((root, node_id)).or_default();
+            waiting.push((connection_id, sender));
+            uploads.peer(root, node_id).state = UploadState::Pending;
+            (answer, root, node_id, connection)
+        };
+        // the provider waits for this, so the request is held until the answer
+        let answer = answer.await;
+        let mut uploads = self.inner.lock().unwrap();
+        match answer {
+            Ok(Approval::Once) => {
+                uploads.approved.insert(connection_id);
+                uploads.start(connection_id, request_id, root, node_id);
+            }
+            Ok(Approval::Always) => uploads.start(connection_id, request_id, root, node_id),
+            Ok(Approval::Deny) => uploads.refuse(Some(root), node_id, &connection),
+            // the peer left or the share stopped before anyone answered
+            Err(_) => {
+                connection.close(1u32.into(), b"not allowed");
+                if uploads.peers.contains_key(&root) {
+                    uploads.peer(root, node_id).state = UploadState::Aborted;
+                }
+            }
+        }
+    }
+
+    fn connected(&self, connection: &Connection) -> anyhow::Result<u64> {
+        let connection_id = connection.stable_id() as u64;
+        let node_id = get_remote_node_id(connection)?;
+        let mut uploads = self.inner.lock().unwrap();
+        uploads
+            .nodes
+            .insert(connection_id, (node_id, connection.clone()));
+        Ok(connection_id)
+    }
+
+    fn disconnected(&self, connection_id: u64) {
+        let mut uploads = self.inner.lock().unwrap();
+        uploads.nodes.remove(&connection_id);
+        uploads.approved.remove(&connection_id);
+        uploads.requests.retain(|(id, _), _| *id != connection_id);
+        // dropping the senders of held requests lets them end
+        for waiting in uploads.waiting.values_mut() {
+            waiting.retain(|(id, _)| *id != connection_id);
+        }
+        uploads.waiting.retain(|_, waiting| !waiting.is_empty());
+    }
+}
+
+impl Uploads {
+    /// Whether to serve `hash`, and for which share, since a blob can be in
+    /// several of them.
+    fn verdict(&self, hash: &Hash, node_id: NodeId, connection_id: u64) -> Verdict {
+        // blobs of stopped shares may stay in the store for the hash cache
+        let Some(shares) = self.shared.get(hash) else {
+            return Verdict::Refuse(None);
+        };
+        let mut verdict = Verdict::Refuse(shares.first().map(|(root, _)| *root));
+        // any share with the hash that lets the node in is enough
+        for (root, access) in shares {
+            if !access.allow.is_empty() && !access.allow.contains(&node_id) {
+                continue;
+            }
+            let approved =
+                self.trusted.contains(&(*root, node_id)) || self.approved.contains(&connection_id);
+            if !access.ask || approved {
+                return Verdict::Serve(*root);
+            }
+            if let Verdict::Refuse(_) = verdict {
+                verdict = Verdict::Ask(*root);
+            }
+        }
+        verdict
+    }
+
+    fn start(&mut self, connection_id: u64, request_id: u64, root: Hash, node_id: NodeId) {
+        if !self.nodes.contains_key(&connection_id) {
+            return;
+        }
+        let request = Request {
+            root,
+            node_id,
+            offset: 0,
+        };
+        self.requests.insert((connection_id, request_id), request);
+        self.active.insert(root, SystemTime::now());
+        // a peer downloading again starts over
+        self.peer(root, node_id).state = UploadState::Transferring;
+    }
+
+    fn refuse(&mut self, root: Option<Hash>, node_id: NodeId, connection: &Connection) {
+        // the provider waits for the request event, so nothing is sent yet
+        connection.close(1u32.into(), b"not allowed");
+        if let Some(root) = root {
+            self.peer(root, node_id).state = UploadState::Refused;
+        }
+    }
+
+    fn peer(&mut self, root: Hash, node_id: NodeId) -> &mut PeerUpload {
+        let peers = self.peers.entry(root).or_default();
+        let index = match peers.iter().position(|peer| peer.node_id == node_id) {
+            Some(index) => index,
+            None => {
+                peers.push(PeerUpload {
+                    node_id,
+                    blobs: 0,
+                    bytes: 0,
+                    state: UploadState::Transferring,
+                });
+                peers.len() - 1
+            }
+        };
+        &mut peers[index]
+    }
+
+    /// Move the offset of a request forward and count the difference.
+    fn advance(&mut self, key: (u64, u64), hash: Hash, offset: u64) -> Option<&mut PeerUpload> {
+        let request = self.requests.get_mut(&key)?;
+        // a receiver that only asks for the size of a blob gets its last chunk
+        // group, so a blob is not always sent from its start
+        let start = match request.offset {
+            0 => offset.saturating_sub(1) / CHUNK_GROUP_SIZE * CHUNK_GROUP_SIZE,
+            start => start,
+        };
+        let delta = offset.saturating_sub(start);
+        request.offset = offset;
+        let (root, node_id) = (request.root, request.node_id);
+        self.active.insert(root, SystemTime::now());
+        if self
+            .files
+            .get(&root)
+            .is_some_and(|files| files.contains(&hash))
+        {
+            let sent = self.sent.entry((root, node_id)).or_default();
+            *sent.bytes.entry(hash).or_default() += delta;
+        }
+        let peer = self.peer(root, node_id);
+        peer.bytes += delta;
+        Some(peer)
+    }
+
+    /// Count a download once a node got every file of the share, over any
+    /// number of requests, so a receiver that asks for the sizes first or
+    /// resumes on a new connection is counted once.
+    fn complete(&mut self, key: (u64, u64), hash: Hash, size: u64) {
+        let Some(request) = self.requests.get(&key) else {
+            return;
+        };
+        let (root, node_id) = (request.root, request.node_id);
+        let Some(files) = self.files.get(&root) else {
+            return;
+        };
+        let Some(sent) = self.sent.get_mut(&(root, node_id)) else {
+            return;
+        };
+        if sent.bytes.get(&hash).copied().unwrap_or_default() >= size {
+            sent.files.insert(hash);
+        }
+        if !files.is_subset(&sent.files) {
+            return;
+        }
+        self.sent.remove(&(root, node_id));
+        *self.downloads.entry(root).or_default() += 1;
+    }
+
+    fn finish(&mut self, key: (u64, u64), state: UploadState) {
+        if let Some(request) = self.requests.remove(&key) {
+            self.active.insert(request.root, SystemTime::now());
+            self.peer(request.root, request.node_id).state = state;
+        }
+    }
+}
+
+impl CustomEventSender for UploadTracker {
+    fn send(&self, event: Event) -> BoxFuture<()> {
+        let Event::GetRequestReceived {
+            connection_id,
+            request_id,
+            hash,
+        } = event
+        else {
+            self.try_send(event);
+            return Box::pin(std::future::ready(()));
+        };
+        let this = self.clone();
+        Box::pin(async move { this.request_received(connection_id, request_id, hash).await })
+    }
+
+    fn try_send(&self, event: Event) {
+        let mut uploads = self.inner.lock().unwrap();
+        match event {
+            // without waiting, requests that need approval are refused
+            Event::GetRequestReceived {
+                connection_id,
+                request_id,
+                hash,
+            } => {
+                let Some((node_id, connection)) = uploads.nodes.get(&connection_id).cloned() else {
+                    return;
+                };
+                match uploads.verdict(&hash, node_id, connection_id) {
+                    Verdict::Serve(root) => uploads.start(connection_id, request_id, root, node_id),
+                    Verdict::Ask(root) => uploads.refuse(Some(root), node_id, &connection),
+                    Verdict::Refuse(root) => uploads.refuse(root, node_id, &connection),
+                }
+            }
+            Event::TransferProgress {
+                connection_id,
+                request_id,
+                hash,
+                end_offset,
+            } => {
+                uploads.advance((connection_id, request_id), hash, end_offset);
+            }
+            Event::TransferBlobCompleted {
+                connection_id,
+                request_id,
+                hash,
+                size,
+                ..
+            } => {
+                let key = (connection_id, request_id);
+                if let Some(peer) = uploads.advance(key, hash, size) {
+                    peer.blobs += 1;
+                }
+                uploads.complete(key, hash, size);
+                // the next blob of the collection starts at offset 0 again
+                if let Some(request) = uploads.requests.get_mut(&key) {
+                    request.offset = 0;
+                }
+            }
+            Event::TransferCompleted {
+                connection_id,
+                request_id,
+                ..
+            } => uploads.finish((connection_id, request_id), UploadState::Done),
+            Event::TransferAborted {
+                connection_id,
+                request_id,
+                ..
+            } => uploads.finish((connection_id, request_id), UploadState::Aborted),
+            _ => {}
+        }
+    }
+}
+
+/// Serves blobs like [`Blobs`], but tells the [`UploadTracker`] which node each
+/// connection belongs to, since the provider events only carry connection ids.
+#[derive(Debug, Clone)]
+pub(crate) struct TrackedBlobs {
+    blobs: Blobs<Store>,
+    uploads: UploadTracker,
+}
+
+impl TrackedBlobs {
+    pub(crate) fn new(blobs: Blobs<Store>, uploads: UploadTracker) -> Self {
+        Self { blobs, uploads }
+    }
+}
+
+impl ProtocolHandler for TrackedBlobs {
+    fn accept(&self, conn: Connecting) -> BoxFuture<anyhow::Result<()>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let connection = conn.await?;
+            let connection_id = this.uploads.connected(&connection)?;
+            provider::handle_connection(
+                connection,
+                this.blobs.store().clone(),
+                EventSender::from(this.uploads.clone()),
+                this.blobs.rt().clone(),
+            )
+            .await;
+            this.uploads.disconnected(connection_id);
+            Ok(())
+        })
+    }
+
+    fn shutdown(&self) -> BoxFuture<()> {
+        ProtocolHandler::shutdown(&self.blobs)
+    }
+}
@@ -0,0 +1,311 @@
+use indicatif::{HumanBytes, HumanDuration};
+use iroh::NodeId;
+use sendme::backend::cache;
+use sendme::backend::identity;
+use sendme::backend::scratch;
+use sendme::backend::upload::UploadState;
+use sendme::interconnect::{
+    Approval, CacheArgs, Commands, CtlArgs, GcArgs, IdentityArgs, IdentityCommand, ReceiveArgs,
+    SendArgs, TransferEvent,
+};
+use sendme::session::{ReceiveSession, SendSession};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+/// Run a subcommand without opening a window.
+pub fn run(command: Commands) -> anyhow::Result<()> {
+    let runtime = Runtime::new()?;
+    runtime.block_on(async move {
+        match command {
+            Commands::Send(args) => send_cli(args).await,
+            Commands::Receive(args) => receive_cli(args).await,
+            #[cfg(unix)]
+            Commands::Daemon(args) => sendme::daemon::run(args).await,
+            Commands::Ctl(args) => ctl_cli(args).await,
+            Commands::Identity(args) => identity_cli(args),
+            Commands::Gc(args) => gc_cli(args),
+            Commands::Cache(args) => cache_cli(args),
+            #[cfg(not(unix))]
+            Commands::Daemon(_) => anyhow::bail!("the daemon needs unix domain sockets"),
+        }
+    })
+}
+
+async fn send_cli(args: SendArgs) -> anyhow::Result<()> {
+    let json = args.common.json;
+    let ask = args.access.ask;
+    anyhow::ensure!(
+        !ask || !json,
+        "--ask reads the answers from the terminal and can not be combined with --json"
+    );
+    anyhow::ensure!(
+        !ask || args.paths.iter().all(|path| path != Path::new("-")),
+        "--ask reads the answers from stdin, so stdin can not be shared"
+    );
+    let mut handle = SendSession::from_args(args).spawn();
+    let (pending_sender, mut pending) = mpsc::unbounded_channel();
+    if !json {
+        let events = handle.events();
+        tokio::spawn(async move {
+            while let Ok(event) = events.recv().await {
+                match event {
+                    TransferEvent::TicketIssued { ticket, .. } => {
+                        println!("to get this data, use");
+                        println!("sendme receive {ticket}");
+                    }
+                    TransferEvent::PeerProgress(peer) => match peer.state {
+                        UploadState::Transferring => {}
+                        UploadState::Done => println!(
+                            "{} downloaded {} blobs, {}",
+                            peer.node_id.fmt_short(),
+                            peer.blobs,
+                            HumanBytes(peer.bytes)
+                        ),
+                        UploadState::Aborted => println!(
+                            "{} aborted after {}",
+                            peer.node_id.fmt_short(),
+                            HumanBytes(peer.bytes)
+                        ),
+                        UploadState::Refused => {
+                            println!("refused {}, it is not allowed", peer.node_id)
+                        }
+                        UploadState::Pending => {
+                            pending_sender.send(peer.node_id).ok();
+                        }
+                    },
+                    _ => {}
+                }
+            }
+        });
+    }
+    let mut answers = if ask {
+        read_lines()
+    } else {
+        mpsc::unbounded_channel().1
+    };
+    // peers are asked about one at a time, in the order they arrived
+    let mut waiting = VecDeque::new();
+    let stopped = loop {
+        tokio::select! {
+            res = handle.wait() => break Some(res),
+            _ = tokio::signal::ctrl_c() => break None,
+            Some(node_id) = pending.recv() => {
+                if !waiting.contains(&node_id) {
+                    waiting.push_back(node_id);
+                    if waiting.len() == 1 {
+                        prompt(node_id);
+                    }
+                }
+            }
+            Some(line) = answers.recv() => {
+                let Some(node_id) = waiting.front().copied() else {
+                    continue;
+                };
+                match line.trim().parse::<Approval>() {
+                    Ok(approval) => {
+                        handle.decide(node_id, approval);
+                        waiting.pop_front();
+                        if let Some(next) = waiting.front() {
+                            prompt(*next);
+                        }
+                    }
+                    Err(_) => prompt(node_id),
+                }
+            }
+        }
+    };
+    match stopped {
+        Some(res) => res?,
+        None => {
+            handle.cancel();
+            handle.wait().await?
+        }
+    };
+    Ok(())
+}
+
+fn prompt(node_id: NodeId) {
+    println!("{node_id} wants to download, answer once, always or deny:");
+}
+
+/// Read stdin line by line on a thread that doesn't keep the process alive.
+fn read_lines() -> mpsc::UnboundedReceiver<String> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+async fn receive_cli(args: ReceiveArgs) -> anyhow::Result<()> {
+    let json = args.common.json;
+    let outcome = ReceiveSession::from_args(args).spawn().wait().await?;
+    if !json {
+        let stats = outcome.stats;
+        eprintln!(
+            "Transferred {} in {}, {}/s",
+            HumanBytes(stats.bytes_read),
+            HumanDuration(stats.elapsed),
+            HumanBytes((stats.bytes_read as f64 / stats.elapsed.as_secs_f64()) as u64)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn ctl_cli(args: CtlArgs) -> anyhow::Result<()> {
+    use sendme::daemon::Client;
+    use sendme::interconnect::CtlCommand;
+
+    let client = Client::new(args.socket);
+    let value = match args.command {
+        CtlCommand::Add {
+            paths,
+            options,
+            access,
+            expiry,
+        } => {
+            let share = client.add_share(&paths, &options, &access, &expiry).await?;
+            serde_json::to_value(share)?
+        }
+        CtlCommand::List => serde_json::to_value(client.list_shares().await?)?,
+        CtlCommand::Share { id } => serde_json::to_value(client.share(id).await?)?,
+        CtlCommand::Stop { id } => {
+            client.stop_share(id).await?;
+            return Ok(());
+        }
+        CtlCommand::Decide {
+            id,
+            node_id,
+            approval,
+        } => {
+            client.decide(id, node_id, approval).await?;
+            return Ok(());
+        }
+        CtlCommand::Receive { ticket, target } => {
+            serde_json::to_value(client.receive(ticket, &target).await?)?
+        }
+        CtlCommand::Downloads => serde_json::to_value(client.list_downloads().await?)?,
+        CtlCommand::Download { id } => serde_json::to_value(client.download(id).await?)?,
+    };
+    println!("{}", serde_json::to_string_pretty(&value)?);
+    Ok(())
+}
+
+fn gc_cli(args: GcArgs) -> anyhow::Result<()> {
+    let paths = if args.all {
+        scratch::leftovers(args.data_dir.as_deref())?
+            .into_iter()
+            .map(|leftover| leftover.path)
+            .collect()
+    } else {
+        args.paths
+    };
+    if !paths.is_empty() {
+        for path in paths {
+            scratch::remove_leftover(args.data_dir.as_deref(), &path)?;
+            println!("deleted {}", path.display());
+        }
+        return Ok(());
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let leftovers = scratch::leftovers(args.data_dir.as_deref())?;
+    if leftovers.is_empty() {
+        println!("nothing left behind");
+    }
+    for leftover in leftovers {
+        let age = Duration::from_secs(now.saturating_sub(leftover.modified));
+        println!(
+            "{}  {} store, {}, last written {} ago",
+            leftover.path.display(),
+            leftover.kind,
+            HumanBytes(leftover.size),
+            HumanDuration(age)
+        );
+        if let Some(ticket) = &leftover.ticket {
+            // the download continues in the same store
+            let data_dir = args
+                .data_dir
+                .as_ref()
+                .map(|dir| format!(" --data-dir {}", dir.display()))
+                .unwrap_or_default();
+            println!("    resume with: sendme receive {ticket}{data_dir}");
+        }
+    }
+    Ok(())
+}
+
+fn cache_cli(args: CacheArgs) -> anyhow::Result<()> {
+    if args.clear {
+        cache::clear(args.data_dir.as_deref())?;
+        println!("cleared");
+        return Ok(());
+    }
+    let info = cache::info(args.data_dir.as_deref())?;
+    println!("{}", info.dir.display());
+    println!(
+        "{} file(s), {}, taking {} on disk",
+        info.files,
+        HumanBytes(info.size),
+        HumanBytes(info.disk_usage)
+    );
+    if info.changed > 0 {
+        println!("{} of them changed and will be hashed again", info.changed);
+    }
+    if info.in_use {
+        println!("in use by a running transfer");
+    }
+    Ok(())
+}
+
+fn identity_cli(args: IdentityArgs) -> anyhow::Result<()> {
+    match args.command {
+        IdentityCommand::Show { name } => {
+            let name = name.map_or_else(identity::active, Ok)?;
+            println!("{}", identity::load(&name)?.public());
+        }
+        IdentityCommand::List => {
+            for identity in identity::list()? {
+                let marker = if identity.active { "*" } else { " " };
+                println!("{marker} {} {}", identity.name, identity.node_id);
+            }
+        }
+        IdentityCommand::New { name } => println!("{}", identity::create(&name)?.public()),
+        IdentityCommand::Use { name } => identity::set_active(&name)?,
+        IdentityCommand::Rotate { name } => {
+            let name = name.map_or_else(identity::active, Ok)?;
+            println!("{}", identity::rotate(&name)?.public());
+        }
+        IdentityCommand::Export { name } => {
+            let name = name.map_or_else(identity::active, Ok)?;
+            println!("{}", identity::export(&name)?);
+        }
+        IdentityCommand::Import { name, secret } => {
+            let secret = match secret {
+                Some(secret) => secret,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            println!("{}", identity::import(&name, &secret)?.public());
+        }
+        IdentityCommand::Remove { name } => identity::remove(&name)?,
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn ctl_cli(_args: CtlArgs) -> anyhow::Result<()> {
+    anyhow::bail!("the daemon needs unix domain sockets")
+}
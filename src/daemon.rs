@@ -0,0 +1,554 @@
+use crate::backend::report::Reporter;
+use crate::backend::send::{report_peers, report_stale, PEER_POLL};
+use crate::backend::share::{ShareId, ShareInfo, ShareManager};
+use crate::interconnect::{
+    display_from_str, AccessOptions, Approval, CommonArgs, DaemonArgs, ExpiryOptions, ExportTarget,
+    ImportOptions, RelayModeOption, SendArgs, SendOutcome, TransferEvent,
+};
+use crate::session::ReceiveSession;
+use anyhow::Context;
+use iroh::NodeId;
+use iroh_blobs::ticket::BlobTicket;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::watch::Receiver;
+
+pub type DownloadId = u64;
+
+/// A request to the daemon, sent as one line of JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Share absolute paths in one ticket.
+    ///
+    /// Closing the connection before the answer cancels the import.
+    AddShare {
+        paths: Vec<PathBuf>,
+        #[serde(default)]
+        options: ImportOptions,
+        #[serde(default)]
+        access: AccessOptions,
+        #[serde(default)]
+        expiry: ExpiryOptions,
+    },
+    ListShares,
+    Share {
+        id: ShareId,
+    },
+    StopShare {
+        id: ShareId,
+    },
+    /// Answer a peer that waits for approval to download a share.
+    Decide {
+        id: ShareId,
+        #[serde(with = "display_from_str")]
+        node_id: NodeId,
+        approval: Approval,
+    },
+    /// Download a ticket into an absolute directory.
+    Receive {
+        #[serde(with = "display_from_str")]
+        ticket: BlobTicket,
+        target: PathBuf,
+    },
+    ListDownloads,
+    Download {
+        id: DownloadId,
+    },
+}
+
+/// The answer to a [`Request`], sent as one line of JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Share(ShareInfo),
+    Shares { shares: Vec<ShareInfo> },
+    Download(DownloadInfo),
+    Downloads { downloads: Vec<DownloadInfo> },
+    Error { message: String },
+}
+
+/// A download started through the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadInfo {
+    pub id: DownloadId,
+    #[serde(with = "display_from_str")]
+    pub ticket: BlobTicket,
+    /// The directory the data is exported into.
+    pub target: PathBuf,
+    pub state: DownloadState,
+    /// Bytes downloaded so far.
+    pub offset: u64,
+    pub total_size: u64,
+    /// The exported file or top level directory, once done.
+    pub path: Option<PathBuf>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadState {
+    Connecting,
+    Downloading,
+    Done,
+    Failed,
+}
+
+/// Serve shares from one endpoint and store, controlled through a unix socket,
+/// until ctrl-c.
+pub async fn run(args: DaemonArgs) -> anyhow::Result<()> {
+    if args.socket.exists() {
+        anyhow::ensure!(
+            UnixStream::connect(&args.socket).await.is_err(),
+            "a daemon is already listening on {}",
+            args.socket.display()
+        );
+        // left over from a daemon that did not shut down cleanly
+        tokio::fs::remove_file(&args.socket).await?;
+    }
+    let (shares, warnings) = ShareManager::persistent(&args.common, args.ticket_type).await?;
+    for warning in warnings {
+        eprintln!("warning: {warning}");
+    }
+    let listener = bind_private(&args.socket)?;
+    eprintln!("listening on {}", args.socket.display());
+    let daemon = Daemon {
+        shares: shares.clone(),
+        relay: args.common.relay.clone(),
+        identity: args.common.identity.clone(),
+        data_dir: args.common.data_dir.clone(),
+        downloads: Default::default(),
+        next_download: Default::default(),
+    };
+    loop {
+        tokio::select! {
+            res = listener.accept() => {
+                let (stream, _) = res?;
+                let daemon = daemon.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = daemon.serve(stream).await {
+                        eprintln!("control connection failed: {e:#}");
+                    }
+                });
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    drop(listener);
+    tokio::fs::remove_file(&args.socket).await.ok();
+    shares.shutdown().await
+}
+
+/// Bind the control socket so only the user running the daemon can ever
+/// connect: it is bound in a new directory nobody else can enter, made private,
+/// and only then moved to `socket`.
+fn bind_private(socket: &Path) -> anyhow::Result<UnixListener> {
+    let parent = match socket.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(parent)?;
+    let private = parent.join(format!(".sendme-{}", std::process::id()));
+    // left over from a daemon with the same pid that crashed
+    std::fs::remove_dir_all(&private).ok();
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = (|| {
+        let path = private.join("sendme.sock");
+        let listener = UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&path, socket)?;
+        anyhow::Ok(listener)
+    })();
+    std::fs::remove_dir(&private).ok();
+    bound.with_context(|| format!("can not listen on {}", socket.display()))
+}
+
+#[derive(Debug, Clone)]
+struct Daemon {
+    shares: ShareManager,
+    /// What downloads take over from the arguments of the daemon.
+    relay: RelayModeOption,
+    identity: Option<String>,
+    data_dir: Option<PathBuf>,
+    downloads: Arc<Mutex<BTreeMap<DownloadId, DownloadInfo>>>,
+    next_download: Arc<AtomicU64>,
+}
+
+impl Daemon {
+    /// Answer requests until the client closes the connection.
+    ///
+    /// A client that closes it before the answer cancels the request, which
+    /// removes what an import added so far.
+    async fn serve(self, stream: UnixStream) -> anyhow::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let mut next = lines.next_line().await?;
+        while let Some(line) = next.take() {
+            let answer = self.answer(&line);
+            tokio::pin!(answer);
+            let response = tokio::select! {
+                response = &mut answer => response,
+                following = lines.next_line() => match following? {
+                    None => return Ok(()),
+                    // requests are answered in order
+                    Some(following) => {
+                        next = Some(following);
+                        answer.await
+                    }
+                },
+            };
+            let mut line = serde_json::to_string(&response)?;
+            line.push('\n');
+            write.write_all(line.as_bytes()).await?;
+            if next.is_none() {
+                next = lines.next_line().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn answer(&self, line: &str) -> Response {
+        match serde_json::from_str::<Request>(line) {
+            Ok(request) => self
+                .handle(request)
+                .await
+                .unwrap_or_else(|e| Response::Error {
+                    message: format!("{e:#}"),
+                }),
+            Err(e) => Response::Error {
+                message: format!("invalid request: {e}"),
+            },
+        }
+    }
+
+    async fn handle(&self, request: Request) -> anyhow::Result<Response> {
+        Ok(match request {
+            Request::AddShare {
+                paths,
+                options,
+                access,
+                expiry,
+            } => {
+                for path in &paths {
+                    anyhow::ensure!(
+                        path.is_absolute(),
+                        "path {} is not absolute",
+                        path.display()
+                    );
+                }
+                let share = self
+                    .shares
+                    .add(
+                        paths,
+                        String::new(),
+                        &options,
+                        &access,
+                        &expiry,
+                        Reporter::new(),
+                    )
+                    .await?;
+                Response::Share(share)
+            }
+            Request::ListShares => Response::Shares {
+                shares: self.shares.list(),
+            },
+            Request::Share { id } => Response::Share(
+                self.shares
+                    .get(id)
+                    .context(format!("no share with id {id}"))?,
+            ),
+            Request::StopShare { id } => {
+                self.shares.stop(id).await?;
+                Response::Ok
+            }
+            Request::Decide {
+                id,
+                node_id,
+                approval,
+            } => {
+                self.shares.decide(id, node_id, approval)?;
+                Response::Ok
+            }
+            Request::Receive { ticket, target } => {
+                anyhow::ensure!(
+                    target.is_absolute(),
+                    "target {} is not absolute",
+                    target.display()
+                );
+                Response::Download(self.receive(ticket, target))
+            }
+            Request::ListDownloads => Response::Downloads {
+                downloads: self.downloads.lock().unwrap().values().cloned().collect(),
+            },
+            Request::Download { id } => {
+                let downloads = self.downloads.lock().unwrap();
+                let download = downloads
+                    .get(&id)
+                    .context(format!("no download with id {id}"))?;
+                Response::Download(download.clone())
+            }
+        })
+    }
+
+    /// Start a download and keep its [`DownloadInfo`] up to date.
+    fn receive(&self, ticket: BlobTicket, target: PathBuf) -> DownloadInfo {
+        let id = self.next_download.fetch_add(1, Ordering::Relaxed);
+        let info = DownloadInfo {
+            id,
+            ticket: ticket.clone(),
+            target: target.clone(),
+            state: DownloadState::Connecting,
+            offset: 0,
+            total_size: 0,
+            path: None,
+            error: None,
+        };
+        self.downloads.lock().unwrap().insert(id, info.clone());
+        // the endpoint of the daemon is bound already, so its addresses don't carry over
+        let common = CommonArgs {
+            relay: self.relay.clone(),
+            identity: self.identity.clone(),
+            data_dir: self.data_dir.clone(),
+            ..Default::default()
+        };
+        let mut handle = ReceiveSession::new(ticket)
+            .common(common)
+            .target(ExportTarget::Dir(target))
+            .spawn();
+        let downloads = self.downloads.clone();
+        let update = move |f: &dyn Fn(&mut DownloadInfo)| {
+            if let Some(info) = downloads.lock().unwrap().get_mut(&id) {
+                f(info);
+            }
+        };
+        tokio::spawn(async move {
+            let events = handle.events();
+            while let Ok(event) = events.recv().await {
+                if let TransferEvent::DownloadProgress {
+                    offset, total_size, ..
+                } = event
+                {
+                    update(&|info| {
+                        info.state = DownloadState::Downloading;
+                        info.offset = offset;
+                        info.total_size = total_size;
+                    });
+                }
+            }
+            match handle.wait().await {
+                Ok(outcome) => update(&|info| {
+                    info.state = DownloadState::Done;
+                    info.offset = info.total_size;
+                    info.path = outcome.path.clone();
+                }),
+                Err(e) => update(&|info| {
+                    info.state = DownloadState::Failed;
+                    info.error = Some(format!("{e:#}"));
+                }),
+            }
+        });
+        info
+    }
+}
+
+/// Talks to a daemon over its control socket.
+#[derive(Debug, Clone)]
+pub struct Client {
+    socket: PathBuf,
+}
+
+impl Client {
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: socket.into(),
+        }
+    }
+
+    /// Send a request, error responses are turned into errors.
+    pub async fn request(&self, request: &Request) -> anyhow::Result<Response> {
+        let stream = UnixStream::connect(&self.socket)
+            .await
+            .with_context(|| format!("no daemon listening on {}", self.socket.display()))?;
+        let (read, mut write) = stream.into_split();
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        write.write_all(line.as_bytes()).await?;
+        let line = BufReader::new(read)
+            .lines()
+            .next_line()
+            .await?
+            .context("daemon closed the connection")?;
+        match serde_json::from_str(&line)? {
+            Response::Error { message } => Err(anyhow::anyhow!(message)),
+            response => Ok(response),
+        }
+    }
+
+    pub async fn add_share(
+        &self,
+        paths: &[PathBuf],
+        options: &ImportOptions,
+        access: &AccessOptions,
+        expiry: &ExpiryOptions,
+    ) -> anyhow::Result<ShareInfo> {
+        let paths = paths
+            .iter()
+            .map(|path| path.canonicalize())
+            .collect::<std::io::Result<_>>()?;
+        let request = Request::AddShare {
+            paths,
+            options: options.clone(),
+            access: access.clone(),
+            expiry: expiry.clone(),
+        };
+        match self.request(&request).await? {
+            Response::Share(share) => Ok(share),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn list_shares(&self) -> anyhow::Result<Vec<ShareInfo>> {
+        match self.request(&Request::ListShares).await? {
+            Response::Shares { shares } => Ok(shares),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn share(&self, id: ShareId) -> anyhow::Result<ShareInfo> {
+        match self.request(&Request::Share { id }).await? {
+            Response::Share(share) => Ok(share),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn stop_share(&self, id: ShareId) -> anyhow::Result<()> {
+        match self.request(&Request::StopShare { id }).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn decide(
+        &self,
+        id: ShareId,
+        node_id: NodeId,
+        approval: Approval,
+    ) -> anyhow::Result<()> {
+        let request = Request::Decide {
+            id,
+            node_id,
+            approval,
+        };
+        match self.request(&request).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn receive(&self, ticket: BlobTicket, target: &Path) -> anyhow::Result<DownloadInfo> {
+        let target = std::path::absolute(target)?;
+        match self.request(&Request::Receive { ticket, target }).await? {
+            Response::Download(download) => Ok(download),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn list_downloads(&self) -> anyhow::Result<Vec<DownloadInfo>> {
+        match self.request(&Request::ListDownloads).await? {
+            Response::Downloads { downloads } => Ok(downloads),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn download(&self, id: DownloadId) -> anyhow::Result<DownloadInfo> {
+        match self.request(&Request::Download { id }).await? {
+            Response::Download(download) => Ok(download),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> anyhow::Error {
+    anyhow::anyhow!("unexpected response from daemon: {response:?}")
+}
+
+/// Share through a daemon instead of a local endpoint.
+///
+/// Only an explicit cancel stops the share, if the cancel sender is dropped
+/// the share keeps running in the daemon.
+pub(crate) async fn send(
+    client: Client,
+    args: SendArgs,
+    reporter: Reporter,
+    mut cancel_receiver: Receiver<bool>,
+    mut approvals: UnboundedReceiver<(NodeId, Approval)>,
+) -> anyhow::Result<SendOutcome> {
+    anyhow::ensure!(
+        args.paths.iter().all(|path| path != Path::new("-")),
+        "stdin can not be shared through the daemon"
+    );
+    let add = client.add_share(&args.paths, &args.import, &args.access, &args.expiry);
+    let share = tokio::select! {
+        share = add => share?,
+        _ = cancel_receiver.wait_for(|cancelled| *cancelled) => {
+            // dropping the request cancels the import in the daemon
+            reporter.emit(TransferEvent::Stopped);
+            anyhow::bail!("cancelled while importing");
+        }
+    };
+    for warning in &share.warnings {
+        reporter.warn(warning);
+    }
+    reporter.emit(TransferEvent::TicketIssued {
+        ticket: share.ticket.clone(),
+        hash: crate::backend::print_hash(&share.hash, args.common.format),
+        size: share.size,
+        files: share.files,
+    });
+    let mut peers = Vec::new();
+    let mut stale = 0;
+    let mut poll = tokio::time::interval(PEER_POLL);
+    loop {
+        tokio::select! {
+            res = cancel_receiver.wait_for(|cancelled| *cancelled) => {
+                if res.is_ok() {
+                    client.stop_share(share.id).await?;
+                    reporter.emit(TransferEvent::Stopped);
+                }
+                break;
+            }
+            _ = poll.tick() => {
+                // the daemon forgets shares once they expire
+                let Ok(current) = client.share(share.id).await else {
+                    reporter.log("the share expired");
+                    reporter.emit(TransferEvent::Stopped);
+                    break;
+                };
+                report_peers(&reporter, &mut peers, current.peers);
+                report_stale(&reporter, &mut stale, &current.stale);
+            }
+            Some((node_id, approval)) = approvals.recv() => {
+                if let Err(e) = client.decide(share.id, node_id, approval).await {
+                    reporter.warn(e);
+                }
+            }
+        }
+    }
+    Ok(SendOutcome {
+        ticket: share.ticket,
+        hash: share.hash,
+        size: share.size,
+        files: share.files,
+    })
+}
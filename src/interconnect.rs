@@ -1,22 +1,30 @@
-use iroh::{NodeAddr, RelayMap, RelayMode, RelayUrl};
+use crate::backend::upload::{PeerUpload, UploadState};
+use clap::{Parser, Subcommand};
+use iroh::{NodeAddr, NodeId, RelayMap, RelayMode, RelayUrl};
+use iroh_blobs::format::collection::Collection;
+use iroh_blobs::get::Stats;
 use iroh_blobs::ticket::BlobTicket;
 use iroh_blobs::Hash;
-use serde::{Deserialize, Serialize};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
 use std::fmt::{Display, Formatter};
 use std::net::{SocketAddrV4, SocketAddrV6};
 use std::path::PathBuf;
 use std::str::FromStr;
-use iroh_blobs::get::Stats;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub enum ViewUpdate {
     Nothing,
     Ticket(BlobTicket),
     Progress(ViewProgress),
-    DownloadDone{
+    /// Hashing the files of a new share.
+    Import(ViewImport),
+    DownloadDone {
         stats: Stats,
-        path: String
-    }
+        path: String,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -24,7 +32,175 @@ pub struct ViewProgress {
     pub total_size: u64,
     pub total_files: usize,
     pub progress_value: u64,
-    pub bytes_per_second: u64
+    pub bytes_per_second: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ViewImport {
+    pub files: usize,
+    pub size: u64,
+    pub hashed: u64,
+    /// The file that was hashed last.
+    pub current: String,
+    pub bytes_per_second: u64,
+}
+
+/// What a share served, returned once it stopped.
+#[derive(Debug, Clone)]
+pub struct SendOutcome {
+    pub ticket: BlobTicket,
+    pub hash: Hash,
+    /// Total size of all files in bytes.
+    pub size: u64,
+    pub files: usize,
+}
+
+/// What a download fetched and where it was exported to.
+#[derive(Debug, Clone)]
+pub struct ReceiveOutcome {
+    pub stats: Stats,
+    /// The exported file or top level directory, `None` when written to stdout.
+    pub path: Option<PathBuf>,
+    /// Every exported file.
+    pub files: Vec<PathBuf>,
+}
+
+/// Everything a transfer reports while it runs.
+///
+/// With `--json` every event is printed as one JSON object per line. The
+/// `event` field holds the snake case variant name, the other field names are
+/// stable.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TransferEvent {
+    /// Hashing the data to send, sizes are in bytes.
+    ImportProgress {
+        files: usize,
+        size: u64,
+        hashed: u64,
+        /// The name of the file that was hashed last.
+        current: String,
+        bytes_per_second: u64,
+    },
+    /// The data is imported and can be fetched with the ticket.
+    TicketIssued {
+        #[serde(serialize_with = "display_from_str::serialize")]
+        ticket: BlobTicket,
+        hash: String,
+        size: u64,
+        files: usize,
+    },
+    /// Connected to the sender.
+    Connected {
+        #[serde(serialize_with = "display_from_str::serialize")]
+        node_id: NodeId,
+    },
+    /// Downloading, sizes are in bytes.
+    DownloadProgress {
+        total_size: u64,
+        total_files: usize,
+        offset: u64,
+        bytes_per_second: u64,
+    },
+    /// The download is complete and exported to `path`.
+    Done {
+        #[serde(serialize_with = "serialize_stats")]
+        stats: Stats,
+        path: String,
+    },
+    /// Something was left out or could not be done, but the transfer goes on.
+    Warning { message: String },
+    /// A peer downloaded more of the share, finished or aborted.
+    PeerProgress(PeerUpload),
+    /// The share was stopped.
+    Stopped,
+    /// The transfer failed.
+    Error { message: String },
+}
+
+impl TransferEvent {
+    /// Whether the event only reports progress, which a later event of the
+    /// same kind supersedes.
+    pub fn is_progress(&self) -> bool {
+        matches!(
+            self,
+            TransferEvent::ImportProgress { .. }
+                | TransferEvent::DownloadProgress { .. }
+                | TransferEvent::PeerProgress(PeerUpload {
+                    state: UploadState::Transferring,
+                    ..
+                })
+        )
+    }
+
+    /// The update the view shows for this event, if any.
+    pub fn view_update(&self) -> Option<ViewUpdate> {
+        match self {
+            TransferEvent::TicketIssued { ticket, .. } => Some(ViewUpdate::Ticket(ticket.clone())),
+            TransferEvent::DownloadProgress {
+                total_size,
+                total_files,
+                offset,
+                bytes_per_second,
+            } => Some(ViewUpdate::Progress(ViewProgress {
+                total_size: *total_size,
+                total_files: *total_files,
+                progress_value: *offset,
+                bytes_per_second: *bytes_per_second,
+            })),
+            TransferEvent::ImportProgress {
+                files,
+                size,
+                hashed,
+                current,
+                bytes_per_second,
+            } => Some(ViewUpdate::Import(ViewImport {
+                files: *files,
+                size: *size,
+                hashed: *hashed,
+                current: current.clone(),
+                bytes_per_second: *bytes_per_second,
+            })),
+            TransferEvent::Done { stats, path } => Some(ViewUpdate::DownloadDone {
+                stats: stats.clone(),
+                path: path.clone(),
+            }),
+            TransferEvent::Stopped => Some(ViewUpdate::Nothing),
+            _ => None,
+        }
+    }
+}
+
+/// Serde helpers for types that round trip through their string form, like tickets.
+pub(crate) mod display_from_str {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+fn serialize_stats<S: Serializer>(stats: &Stats, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut state = serializer.serialize_struct("Stats", 3)?;
+    state.serialize_field("bytes_written", &stats.bytes_written)?;
+    state.serialize_field("bytes_read", &stats.bytes_read)?;
+    state.serialize_field("elapsed_ms", &(stats.elapsed.as_millis() as u64))?;
+    state.end()
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -55,37 +231,81 @@ impl Display for Format {
     }
 }
 
+// Without a subcommand the graphical interface is started.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    #[clap(subcommand)]
+    pub command: Option<Commands>,
+}
 
-#[derive(Debug)]
+#[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Send a file or directory.
     Send(SendArgs),
 
     /// Receive a file or directory.
+    #[clap(visible_alias = "recv")]
     Receive(ReceiveArgs),
+
+    /// Run in the background and serve shares until stopped.
+    ///
+    /// Shares and downloads are managed through a control socket, see `ctl`.
+    Daemon(DaemonArgs),
+
+    /// Control a running daemon.
+    Ctl(CtlArgs),
+
+    /// Show and manage the node identities transfers use.
+    Identity(IdentityArgs),
+
+    /// List or delete the stores that interrupted transfers left behind.
+    Gc(GcArgs),
+
+    /// Show or clear the hashes of shared files that are kept between runs.
+    Cache(CacheArgs),
 }
 
-#[derive(Debug)]
+#[derive(Parser, Debug)]
 pub struct CommonArgs {
     /// The IPv4 address that magic socket will listen on.
     ///
     /// If None, defaults to a random free port, but it can be useful to specify a fixed
     /// port, e.g. to configure a firewall rule.
+    #[clap(long, default_value = None)]
     pub magic_ipv4_addr: Option<SocketAddrV4>,
 
     /// The IPv6 address that magic socket will listen on.
     ///
     /// If None, defaults to a random free port, but it can be useful to specify a fixed
     /// port, e.g. to configure a firewall rule.
+    #[clap(long, default_value = None)]
     pub magic_ipv6_addr: Option<SocketAddrV6>,
 
+    /// The format used to print hashes, "hex" or "cid".
+    #[clap(long, default_value_t = Format::Hex)]
     pub format: Format,
 
     /// The relay URL to use as a home relay,
     ///
     /// Can be set to "disable" to disable relay servers and "default"
     /// to configure default servers.
+    #[clap(long, default_value_t = RelayModeOption::Default)]
     pub relay: RelayModeOption,
+
+    /// Print every transfer event as a JSON object on its own line.
+    #[clap(long)]
+    pub json: bool,
+
+    /// The identity to connect as, see `sendme identity`. Defaults to the
+    /// active one, IROH_SECRET overrides both.
+    #[clap(long, value_name = "NAME")]
+    pub identity: Option<String>,
+
+    /// Where to keep stores and shares, instead of SENDME_DATA_DIR or the
+    /// platform data directory.
+    #[clap(long, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
 }
 
 impl Default for CommonArgs {
@@ -95,6 +315,9 @@ impl Default for CommonArgs {
             magic_ipv6_addr: None,
             magic_ipv4_addr: None,
             format: Format::default(),
+            json: false,
+            identity: None,
+            data_dir: None,
         }
     }
 }
@@ -142,13 +365,19 @@ impl From<RelayModeOption> for RelayMode {
     }
 }
 
-#[derive(Debug)]
+#[derive(Parser, Debug)]
 pub struct SendArgs {
-    /// Path to the file or directory to send.
+    /// Paths to the files or directories to send, all in one ticket.
     ///
-    /// The last component of the path will be used as the name of the data
-    /// being shared.
-    pub path: PathBuf,
+    /// The last component of each path will be used as its name in the data
+    /// being shared, with a number appended if two paths have the same name.
+    /// Use "-" alone to read the data from stdin.
+    #[clap(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// The name of the data read from stdin.
+    #[clap(long, default_value = "stdin")]
+    pub name: String,
 
     /// What type of ticket to use.
     ///
@@ -163,19 +392,370 @@ pub struct SendArgs {
     ///
     /// This is most useful for debugging which methods of connection
     /// establishment work well.
+    #[clap(long, default_value_t = AddrInfoOptions::Id)]
     pub ticket_type: AddrInfoOptions,
 
+    #[clap(flatten)]
+    pub import: ImportOptions,
+
+    #[clap(flatten)]
+    pub access: AccessOptions,
+
+    #[clap(flatten)]
+    pub expiry: ExpiryOptions,
+
+    #[clap(flatten)]
     pub common: CommonArgs,
 }
 
-#[derive(Debug)]
+/// When a share stops by itself. It runs until stopped if nothing is set.
+#[derive(Parser, Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExpiryOptions {
+    /// Stop after this many completed downloads.
+    #[clap(long, value_name = "N")]
+    pub max_downloads: Option<u64>,
+
+    /// Stop this long after the share started, like "30m" or "2h".
+    #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub expire_after: Option<Duration>,
+
+    /// Stop when nothing was downloaded for this long, like "10m".
+    #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Who may download a share.
+#[derive(Parser, Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessOptions {
+    /// Only serve the share to this node id, refusing everyone else even if they
+    /// have the ticket. Can be given several times, anyone may download if left out.
+    #[clap(long = "allow", value_name = "NODE_ID")]
+    pub allow: Vec<NodeId>,
+
+    /// Hold each new peer's download until the sender allows it once, always
+    /// allows the peer or denies it. On the command line the answer is read
+    /// from stdin.
+    #[clap(long)]
+    pub ask: bool,
+}
+
+/// The sender's answer to a peer that waits for approval.
+#[derive(
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    derive_more::Display,
+    derive_more::FromStr,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Approval {
+    /// Serve this connection of the peer.
+    Once,
+    /// Serve the peer this share from now on, without asking again.
+    Always,
+    /// Disconnect the peer.
+    Deny,
+}
+
+/// Which files inside the shared directories are imported.
+///
+/// The shared paths themselves are always included.
+#[derive(Parser, Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImportOptions {
+    /// Only include files matching this glob, in .gitignore syntax relative to
+    /// the shared directory. Can be given several times.
+    #[clap(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Leave out files and directories matching this glob, in .gitignore syntax.
+    /// Can be given several times and wins over --include.
+    #[clap(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Leave out what .gitignore, .ignore and .git/info/exclude files ignore.
+    #[clap(long)]
+    pub gitignore: bool,
+
+    /// Leave out hidden files and directories, whose names start with a dot.
+    #[clap(long)]
+    pub skip_hidden: bool,
+
+    /// What to do with symbolic links: "skip" them with a warning, "follow"
+    /// them and share what they point to, or "preserve" them as links. Links
+    /// that point outside the shared directory are not recreated by receivers.
+    #[clap(long, default_value_t = SymlinkPolicy::Skip)]
+    pub symlinks: SymlinkPolicy,
+
+    /// Also send empty directories, permission bits and modification times.
+    /// Receivers that don't support this get an extra .sendme-metadata.json file.
+    #[clap(long)]
+    pub preserve_metadata: bool,
+
+    /// Compress files with zstd before sending, unless that doesn't make their
+    /// first 64 KiB smaller. Only receivers that support it can restore the
+    /// original files.
+    #[clap(long)]
+    pub compress: bool,
+
+    /// Pack files smaller than 64 KiB into a few larger blobs, which transfer
+    /// much faster than thousands of tiny ones. Packed files are copied into the
+    /// store and not compressed. Only receivers that support it unpack them.
+    #[clap(long)]
+    pub pack: bool,
+
+    /// How files are kept while they are shared: "reference" reads them from
+    /// where they are, and stops serving files that change, "snapshot" copies
+    /// them into the store when the share starts.
+    #[clap(long, default_value_t = ImportModeOption::Reference)]
+    pub import_mode: ImportModeOption,
+}
+
+#[derive(
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Default,
+    Debug,
+    derive_more::Display,
+    derive_more::FromStr,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportModeOption {
+    /// Serve the files from where they are, without a copy.
+    #[default]
+    Reference,
+    /// Copy the files into the store, so later changes don't matter.
+    Snapshot,
+}
+
+#[derive(
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Default,
+    Debug,
+    derive_more::Display,
+    derive_more::FromStr,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Leave links out and warn about them.
+    #[default]
+    Skip,
+    /// Share what the links point to, skipping links that form a loop.
+    Follow,
+    /// Share the links themselves.
+    Preserve,
+}
+
+#[derive(Parser, Debug)]
 pub struct ReceiveArgs {
     /// The ticket to use to connect to the sender.
     pub ticket: BlobTicket,
 
+    /// The directory to export the received data into.
+    ///
+    /// The file or directory is created inside of it, named like the source.
+    /// Missing directories are created.
+    #[clap(long = "target-dir", value_name = "DIR", default_value = ".")]
+    pub target: ExportTarget,
+
+    /// Write the received file to stdout instead of exporting it.
+    ///
+    /// Only works for a single file. The data is verified while it is written,
+    /// and nothing is stored on disk. Can't be combined with `--json`, whose
+    /// events would end up in the data.
+    #[clap(long, conflicts_with_all = ["target", "json"])]
+    pub stdout: bool,
+
+    #[clap(flatten)]
     pub common: CommonArgs,
 }
 
+#[derive(Parser, Debug)]
+pub struct DaemonArgs {
+    /// The control socket to listen on.
+    #[clap(long, default_value_os_t = default_socket_path())]
+    pub socket: PathBuf,
+
+    /// What type of ticket to use, see `send --help`.
+    #[clap(long, default_value_t = AddrInfoOptions::Id)]
+    pub ticket_type: AddrInfoOptions,
+
+    #[clap(flatten)]
+    pub common: CommonArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct GcArgs {
+    /// Leftover stores to delete, as listed.
+    pub paths: Vec<PathBuf>,
+
+    /// Delete all leftover stores.
+    #[clap(long, conflicts_with = "paths")]
+    pub all: bool,
+
+    /// The data directory to look in, see `send --help`.
+    #[clap(long, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct CacheArgs {
+    /// Delete the cache, so every file is hashed again.
+    #[clap(long)]
+    pub clear: bool,
+
+    /// The data directory to look in, see `send --help`.
+    #[clap(long, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct IdentityArgs {
+    #[clap(subcommand)]
+    pub command: IdentityCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IdentityCommand {
+    /// Show the node id of an identity, the active one by default.
+    Show { name: Option<String> },
+    /// List all identities.
+    List,
+    /// Create an identity with a new key.
+    New { name: String },
+    /// Make an identity the one transfers use.
+    Use { name: String },
+    /// Replace the key of an identity, the active one by default.
+    ///
+    /// Peers that know the old node id will not recognize the new one.
+    Rotate { name: Option<String> },
+    /// Print the secret key of an identity, the active one by default.
+    Export { name: Option<String> },
+    /// Store a secret key printed by `export` as a new identity.
+    ///
+    /// The key is read from stdin if it is not given.
+    Import {
+        name: String,
+        secret: Option<String>,
+    },
+    /// Delete an identity that is not in use.
+    Remove { name: String },
+}
+
+#[derive(Parser, Debug)]
+pub struct CtlArgs {
+    /// The control socket of the daemon.
+    #[clap(long, default_value_os_t = default_socket_path())]
+    pub socket: PathBuf,
+
+    #[clap(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CtlCommand {
+    /// Share files and directories in one ticket.
+    Add {
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+        #[clap(flatten)]
+        options: ImportOptions,
+        #[clap(flatten)]
+        access: AccessOptions,
+        #[clap(flatten)]
+        expiry: ExpiryOptions,
+    },
+    /// List the running shares.
+    List,
+    /// Show a running share.
+    Share { id: u64 },
+    /// Stop a share.
+    Stop { id: u64 },
+    /// Answer a peer that waits for approval to download a share.
+    Decide {
+        id: u64,
+        node_id: NodeId,
+        /// "once", "always" or "deny".
+        approval: Approval,
+    },
+    /// Start downloading a ticket in the background.
+    Receive {
+        ticket: BlobTicket,
+        /// The directory to export the received data into.
+        #[clap(long = "target-dir", value_name = "DIR", default_value = ".")]
+        target: PathBuf,
+    },
+    /// List the downloads.
+    Downloads,
+    /// Show the progress of a download.
+    Download { id: u64 },
+}
+
+/// The control socket used if none is given, in a directory of its own in the
+/// runtime directory, or in the local data directory where there is none. Never
+/// in the shared temp directory, where others could replace it.
+pub fn default_socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_default()
+        .join("sendme")
+        .join("sendme.sock")
+}
+
+/// Where `receive` puts the downloaded data.
+#[derive(Clone)]
+pub enum ExportTarget {
+    /// Into the given directory.
+    Dir(PathBuf),
+    /// Ask the user with a file dialog.
+    ///
+    /// Closing the dialog cancels the export.
+    Dialog,
+    /// Ask a callback for the directory.
+    ///
+    /// Returning `None` cancels the export.
+    Callback(Arc<dyn Fn(&Collection) -> Option<PathBuf> + Send + Sync>),
+}
+
+impl Default for ExportTarget {
+    fn default() -> Self {
+        ExportTarget::Dir(PathBuf::from("."))
+    }
+}
+
+impl FromStr for ExportTarget {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ExportTarget::Dir(PathBuf::from(s)))
+    }
+}
+
+impl std::fmt::Debug for ExportTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportTarget::Dir(dir) => f.debug_tuple("Dir").field(dir).finish(),
+            ExportTarget::Dialog => f.write_str("Dialog"),
+            ExportTarget::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
 /// Options to configure what is included in a [`NodeAddr`]
 #[derive(
     Copy,
@@ -202,5 +782,3 @@ pub enum AddrInfoOptions {
     /// Includes the Node ID and the direct addresses.
     Addresses,
 }
-
-
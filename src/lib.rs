@@ -0,0 +1,9 @@
+//! Send files and directories over the network, with NAT hole punching.
+//!
+//! [`session::SendSession`] and [`session::ReceiveSession`] are the entry points,
+//! the window and the command line of the `sendme` binary are built on them.
+pub mod backend;
+#[cfg(unix)]
+pub mod daemon;
+pub mod interconnect;
+pub mod session;
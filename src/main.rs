@@ -1,10 +1,15 @@
 #![windows_subsystem = "windows"]
-mod backend;
-mod interconnect;
+mod cli;
 mod view;
 use crate::view::View;
+use clap::Parser;
+use sendme::interconnect::Args;
 
 fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    if let Some(command) = args.command {
+        return cli::run(command);
+    }
     let res = eframe::run_native(
         "Send Me View",
         eframe::NativeOptions {
@@ -0,0 +1,425 @@
+use crate::backend::receive::receive;
+use crate::backend::report::Reporter;
+use crate::backend::send::send;
+use crate::backend::share::{ShareId, ShareInfo, ShareManager};
+#[cfg(unix)]
+use crate::daemon;
+use crate::interconnect::{
+    AccessOptions, AddrInfoOptions, Approval, CommonArgs, ExpiryOptions, ExportTarget,
+    ImportOptions, ReceiveArgs, ReceiveOutcome, SendArgs, SendOutcome, TransferEvent, ViewUpdate,
+};
+use iroh::NodeId;
+use iroh_blobs::ticket::BlobTicket;
+use std::future::Future;
+use std::path::PathBuf;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::watch::{self, Sender};
+use tokio::task::JoinHandle;
+
+/// How many events are buffered for a handle before new progress events are dropped.
+const EVENT_BUFFER: usize = 1024;
+
+/// How many events are buffered for a handle before any new event is dropped,
+/// if nobody reads them.
+const EVENT_CAPACITY: usize = 4 * EVENT_BUFFER;
+
+/// Serves files and directories until it is cancelled.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use sendme::interconnect::TransferEvent;
+/// use sendme::session::SendSession;
+///
+/// let mut handle = SendSession::new("data").spawn();
+/// while let Ok(event) = handle.events().recv().await {
+///     if let TransferEvent::TicketIssued { ticket, .. } = event {
+///         println!("{ticket}");
+///         break;
+///     }
+/// }
+/// handle.cancel();
+/// let outcome = handle.wait().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct SendSession {
+    args: SendArgs,
+    view_update_sender: Option<Sender<ViewUpdate>>,
+    #[cfg(unix)]
+    daemon: Option<daemon::Client>,
+}
+
+impl SendSession {
+    /// Share the file or directory at `path`, or stdin if it is "-".
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self::from_args(SendArgs {
+            paths: vec![path.into()],
+            name: "stdin".to_string(),
+            ticket_type: AddrInfoOptions::default(),
+            import: ImportOptions::default(),
+            access: AccessOptions::default(),
+            expiry: ExpiryOptions::default(),
+            common: CommonArgs::default(),
+        })
+    }
+
+    pub fn from_args(args: SendArgs) -> Self {
+        Self {
+            args,
+            view_update_sender: None,
+            #[cfg(unix)]
+            daemon: None,
+        }
+    }
+
+    /// Share another file or directory in the same ticket.
+    pub fn add_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.paths.push(path.into());
+        self
+    }
+
+    pub fn common(mut self, common: CommonArgs) -> Self {
+        self.args.common = common;
+        self
+    }
+
+    /// Which files inside the shared directories are included.
+    pub fn import_options(mut self, options: ImportOptions) -> Self {
+        self.args.import = options;
+        self
+    }
+
+    /// Only serve the share to `node_id`, and any other node allowed this way.
+    pub fn allow(mut self, node_id: NodeId) -> Self {
+        self.args.access.allow.push(node_id);
+        self
+    }
+
+    /// Hold each new peer until it is answered with [`SendHandle::decide`].
+    pub fn ask(mut self, ask: bool) -> Self {
+        self.args.access.ask = ask;
+        self
+    }
+
+    /// When the share stops by itself.
+    pub fn expiry(mut self, expiry: ExpiryOptions) -> Self {
+        self.args.expiry = expiry;
+        self
+    }
+
+    pub fn ticket_type(mut self, ticket_type: AddrInfoOptions) -> Self {
+        self.args.ticket_type = ticket_type;
+        self
+    }
+
+    /// The name of the data when sharing stdin.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.args.name = name.into();
+        self
+    }
+
+    /// Also send view updates to `view_update_sender`.
+    pub fn view(mut self, view_update_sender: Sender<ViewUpdate>) -> Self {
+        self.view_update_sender = Some(view_update_sender);
+        self
+    }
+
+    /// Share through a running daemon instead of a local endpoint.
+    ///
+    /// The share then outlives the handle, only [`SendHandle::cancel`] stops it.
+    #[cfg(unix)]
+    pub fn daemon(mut self, client: daemon::Client) -> Self {
+        self.daemon = Some(client);
+        self
+    }
+
+    /// Start sharing on the current tokio runtime.
+    pub fn spawn(self) -> SendHandle {
+        let (cancel_sender, cancel_receiver) = watch::channel(false);
+        let (approval_sender, approvals) = mpsc::unbounded_channel();
+        let (reporter, events) = reporter(self.args.common.json, self.view_update_sender);
+        #[cfg(unix)]
+        if let Some(client) = self.daemon {
+            let share = daemon::send(
+                client,
+                self.args,
+                reporter.clone(),
+                cancel_receiver,
+                approvals,
+            );
+            return SendHandle {
+                events,
+                cancel_sender,
+                approval_sender,
+                task: spawn_reporting(reporter, share),
+            };
+        }
+        let share = send(self.args, reporter.clone(), cancel_receiver, approvals);
+        SendHandle {
+            events,
+            cancel_sender,
+            approval_sender,
+            task: spawn_reporting(reporter, share),
+        }
+    }
+}
+
+/// A running share.
+///
+/// Dropping the handle stops the share, unless it is served by a daemon.
+#[derive(Debug)]
+pub struct SendHandle {
+    events: async_channel::Receiver<TransferEvent>,
+    cancel_sender: watch::Sender<bool>,
+    approval_sender: UnboundedSender<(NodeId, Approval)>,
+    task: JoinHandle<anyhow::Result<SendOutcome>>,
+}
+
+impl SendHandle {
+    /// The events of this share, the receiver is also a `Stream`.
+    ///
+    /// Events that are not read in time are dropped, progress first.
+    pub fn events(&self) -> async_channel::Receiver<TransferEvent> {
+        self.events.clone()
+    }
+
+    /// Stop serving, shut down and remove the temporary store.
+    pub fn cancel(&self) {
+        self.cancel_sender.send_replace(true);
+    }
+
+    /// Answer a peer that waits with [`UploadState::Pending`] to download the share.
+    ///
+    /// [`UploadState::Pending`]: crate::backend::upload::UploadState::Pending
+    pub fn decide(&self, node_id: NodeId, approval: Approval) {
+        self.approval_sender.send((node_id, approval)).ok();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Wait until the share stopped.
+    pub async fn wait(&mut self) -> anyhow::Result<SendOutcome> {
+        (&mut self.task).await?
+    }
+}
+
+/// Downloads the data of a ticket and exports it.
+#[derive(Debug)]
+pub struct ReceiveSession {
+    args: ReceiveArgs,
+    view_update_sender: Option<Sender<ViewUpdate>>,
+}
+
+impl ReceiveSession {
+    pub fn new(ticket: BlobTicket) -> Self {
+        Self::from_args(ReceiveArgs {
+            ticket,
+            target: ExportTarget::default(),
+            stdout: false,
+            common: CommonArgs::default(),
+        })
+    }
+
+    pub fn from_args(args: ReceiveArgs) -> Self {
+        Self {
+            args,
+            view_update_sender: None,
+        }
+    }
+
+    pub fn common(mut self, common: CommonArgs) -> Self {
+        self.args.common = common;
+        self
+    }
+
+    pub fn target(mut self, target: ExportTarget) -> Self {
+        self.args.target = target;
+        self
+    }
+
+    /// Write the single file of the collection to stdout.
+    pub fn stdout(mut self, stdout: bool) -> Self {
+        self.args.stdout = stdout;
+        self
+    }
+
+    /// Also send view updates to `view_update_sender`.
+    pub fn view(mut self, view_update_sender: Sender<ViewUpdate>) -> Self {
+        self.view_update_sender = Some(view_update_sender);
+        self
+    }
+
+    /// Start downloading on the current tokio runtime.
+    pub fn spawn(self) -> ReceiveHandle {
+        let (cancel_sender, mut cancel_receiver) = watch::channel(false);
+        let (reporter, events) = reporter(self.args.common.json, self.view_update_sender);
+        let download = receive(self.args, reporter.clone());
+        let task = spawn_reporting(reporter, async move {
+            tokio::select! {
+                res = download => res,
+                _ = cancel_receiver.wait_for(|cancelled| *cancelled) => {
+                    anyhow::bail!("download cancelled")
+                }
+            }
+        });
+        ReceiveHandle {
+            events,
+            cancel_sender,
+            task,
+        }
+    }
+}
+
+/// A running download.
+#[derive(Debug)]
+pub struct ReceiveHandle {
+    events: async_channel::Receiver<TransferEvent>,
+    cancel_sender: watch::Sender<bool>,
+    task: JoinHandle<anyhow::Result<ReceiveOutcome>>,
+}
+
+impl ReceiveHandle {
+    /// The events of this download, the receiver is also a `Stream`.
+    ///
+    /// Events that are not read in time are dropped, progress first.
+    pub fn events(&self) -> async_channel::Receiver<TransferEvent> {
+        self.events.clone()
+    }
+
+    /// Stop downloading.
+    ///
+    /// Data downloaded so far is kept, receiving the same ticket again resumes.
+    pub fn cancel(&self) {
+        self.cancel_sender.send_replace(true);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Wait until the download is complete and exported.
+    pub async fn wait(&mut self) -> anyhow::Result<ReceiveOutcome> {
+        (&mut self.task).await?
+    }
+}
+
+/// The shares served by this process, or by a daemon.
+#[derive(Debug, Clone)]
+pub enum Shares {
+    Local(ShareManager),
+    #[cfg(unix)]
+    Daemon(daemon::Client),
+}
+
+impl Shares {
+    /// Serve shares from an endpoint in this process.
+    pub async fn local(common: &CommonArgs, ticket_type: AddrInfoOptions) -> anyhow::Result<Self> {
+        Ok(Self::Local(ShareManager::spawn(common, ticket_type).await?))
+    }
+
+    /// Serve shares from an endpoint in this process that are served again the
+    /// next time, see [`ShareManager::persistent`].
+    pub async fn persistent(
+        common: &CommonArgs,
+        ticket_type: AddrInfoOptions,
+    ) -> anyhow::Result<(Self, Vec<String>)> {
+        let (shares, warnings) = ShareManager::persistent(common, ticket_type).await?;
+        Ok((Self::Local(shares), warnings))
+    }
+
+    /// Import files and directories and start serving them in one ticket.
+    ///
+    /// The import of a local share is reported to `reporter`, a daemon imports
+    /// on its own. Dropping the future cancels a local import.
+    pub async fn add(
+        &self,
+        paths: Vec<PathBuf>,
+        options: &ImportOptions,
+        access: &AccessOptions,
+        expiry: &ExpiryOptions,
+        reporter: Reporter,
+    ) -> anyhow::Result<ShareInfo> {
+        match self {
+            Self::Local(shares) => {
+                shares
+                    .add(paths, String::new(), options, access, expiry, reporter)
+                    .await
+            }
+            #[cfg(unix)]
+            Self::Daemon(client) => client.add_share(&paths, options, access, expiry).await,
+        }
+    }
+
+    pub async fn list(&self) -> anyhow::Result<Vec<ShareInfo>> {
+        match self {
+            Self::Local(shares) => Ok(shares.list()),
+            #[cfg(unix)]
+            Self::Daemon(client) => client.list_shares().await,
+        }
+    }
+
+    pub async fn stop(&self, id: ShareId) -> anyhow::Result<()> {
+        match self {
+            Self::Local(shares) => shares.stop(id).await,
+            #[cfg(unix)]
+            Self::Daemon(client) => client.stop_share(id).await,
+        }
+    }
+
+    /// Allow or deny a peer that waits for approval to download a share.
+    pub async fn decide(
+        &self,
+        id: ShareId,
+        node_id: NodeId,
+        approval: Approval,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::Local(shares) => shares.decide(id, node_id, approval),
+            #[cfg(unix)]
+            Self::Daemon(client) => client.decide(id, node_id, approval).await,
+        }
+    }
+
+    /// Stop all local shares, shares of a daemon keep running.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Local(shares) => shares.shutdown().await,
+            #[cfg(unix)]
+            Self::Daemon(_) => Ok(()),
+        }
+    }
+}
+
+fn reporter(
+    json: bool,
+    view_update_sender: Option<Sender<ViewUpdate>>,
+) -> (Reporter, async_channel::Receiver<TransferEvent>) {
+    // progress is limited early, to leave room for the other events
+    let (event_sender, events) = async_channel::bounded(EVENT_CAPACITY);
+    let mut reporter = Reporter::new()
+        .json(json)
+        .events(event_sender, EVENT_BUFFER);
+    if let Some(view_update_sender) = view_update_sender {
+        reporter = reporter.view(view_update_sender);
+    }
+    (reporter, events)
+}
+
+/// Spawn a transfer and report its error, if it fails.
+fn spawn_reporting<T: Send + 'static>(
+    reporter: Reporter,
+    transfer: impl Future<Output = anyhow::Result<T>> + Send + 'static,
+) -> JoinHandle<anyhow::Result<T>> {
+    tokio::spawn(async move {
+        let res = transfer.await;
+        if let Err(e) = &res {
+            reporter.emit(TransferEvent::Error {
+                message: format!("{e:#}"),
+            });
+        }
+        res
+    })
+}
@@ -1,52 +1,145 @@
-use crate::interconnect::{AddrInfoOptions, CommonArgs, ReceiveArgs, SendArgs, ViewUpdate};
+use anyhow::Context as _;
 use arboard::Clipboard;
-use egui::{Context, ProgressBar, Ui};
+use egui::{Color32, Context, ProgressBar, Ui};
 use indicatif::{HumanBytes, HumanDuration};
+use iroh::NodeId;
 use iroh_blobs::ticket::BlobTicket;
+use sendme::backend::identity::{self, Identity};
+use sendme::backend::report::Reporter;
+use sendme::backend::scratch::{self, Leftover, LeftoverKind};
+use sendme::backend::send;
+use sendme::backend::share::{ShareId, ShareInfo};
+use sendme::backend::upload::UploadState;
+#[cfg(unix)]
+use sendme::daemon::Client;
+#[cfg(unix)]
+use sendme::interconnect::default_socket_path;
+use sendme::interconnect::{
+    AccessOptions, AddrInfoOptions, Approval, CommonArgs, ExpiryOptions, ExportTarget,
+    ImportModeOption, ImportOptions, SymlinkPolicy, ViewUpdate,
+};
+use sendme::session::{ReceiveHandle, ReceiveSession, Shares};
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::runtime::Runtime;
 use tokio::sync::watch::{channel, Receiver, Sender};
+use tokio::sync::OnceCell;
 use tokio::task::JoinHandle;
-use crate::backend::receive::receive;
-use crate::backend::send::send;
+
+/// How often the list of shares is refreshed.
+const SHARE_REFRESH: Duration = Duration::from_millis(500);
 
 enum Tab {
     Send,
     Receive,
+    Settings,
 }
 
 pub struct View {
     init: bool,
     tab: Tab,
     path: String,
+    /// Paths that are shared together with the next click on "Send".
+    staged: Vec<PathBuf>,
+    include: String,
+    exclude: String,
+    gitignore: bool,
+    include_hidden: bool,
+    symlinks: SymlinkPolicy,
+    preserve_metadata: bool,
+    compress: bool,
+    pack: bool,
+    /// Copy the files into the store instead of reading them from where they are.
+    snapshot: bool,
+    /// Node ids separated by whitespace, anyone may download if empty.
+    allow: String,
+    ask: bool,
+    /// The limits of the next share as typed, empty for none.
+    max_downloads: String,
+    expire_after: String,
+    idle_timeout: String,
+    /// The staged paths and options the preview was made for.
+    previewed: Option<(Vec<PathBuf>, ImportOptions)>,
+    preview: Sender<Option<String>>,
+    preview_generation: Arc<AtomicU64>,
     ticket: String,
-    sending_handle: Option<JoinHandle<anyhow::Result<()>>>,
-    receiving_handle: Option<JoinHandle<()>>,
+    /// Why the ticket could not be read, until it is changed.
+    ticket_error: Option<String>,
+    save_to_downloads: bool,
+    /// The stored identities, reloaded when the settings are opened.
+    identities: Vec<Identity>,
+    /// The name typed for a new or imported identity.
+    identity_name: String,
+    /// The secret key typed for an imported identity.
+    identity_secret: String,
+    identity_error: Option<String>,
+    /// Stores that interrupted transfers left behind, found at startup.
+    leftovers: Sender<Vec<Leftover>>,
+    leftover_error: Option<String>,
+    /// Served from this process, or by a daemon found at startup so they outlive the window.
+    shares: Arc<OnceCell<Shares>>,
+    share_list: Sender<Vec<ShareInfo>>,
+    share_error: Sender<Option<String>>,
+    imports: Vec<Import>,
+    receiving_handle: Option<ReceiveHandle>,
     tokio_runtime: Runtime,
     receiver: Receiver<ViewUpdate>,
     sender: Sender<ViewUpdate>,
-    cancel_sender: Sender<bool>,
-    cancel_receiver: Receiver<bool>,
+}
+
+/// A share that is imported in the background.
+struct Import {
+    paths: Vec<PathBuf>,
+    task: JoinHandle<()>,
+    progress: Receiver<ViewUpdate>,
 }
 
 impl Default for View {
     fn default() -> Self {
         let (sender, receiver) = channel(ViewUpdate::Nothing);
-        let (cancel_sender, cancel_receiver) = channel(false);
         View {
             init: true,
             tab: Tab::Send,
             path: String::new(),
+            staged: Vec::new(),
+            include: String::new(),
+            exclude: String::new(),
+            gitignore: false,
+            include_hidden: true,
+            symlinks: SymlinkPolicy::default(),
+            preserve_metadata: false,
+            compress: false,
+            pack: false,
+            snapshot: false,
+            allow: String::new(),
+            ask: false,
+            max_downloads: String::new(),
+            expire_after: String::new(),
+            idle_timeout: String::new(),
+            previewed: None,
+            preview: channel(None).0,
+            preview_generation: Default::default(),
             ticket: String::new(),
-            sending_handle: None,
+            ticket_error: None,
+            save_to_downloads: false,
+            identities: Vec::new(),
+            identity_name: String::new(),
+            identity_secret: String::new(),
+            identity_error: None,
+            leftovers: channel(Vec::new()).0,
+            leftover_error: None,
+            shares: Default::default(),
+            share_list: channel(Vec::new()).0,
+            share_error: channel(None).0,
+            imports: Vec::new(),
             receiving_handle: None,
             tokio_runtime: Runtime::new().unwrap(),
             sender,
             receiver,
-            cancel_sender,
-            cancel_receiver,
         }
     }
 }
@@ -63,7 +156,8 @@ impl eframe::App for View {
         });
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            if self.sending_handle.is_none() && self.receiving_handle.is_none() {
+            self.show_leftover_notice(ui);
+            if self.receiving_handle.is_none() {
                 ui.horizontal(|ui| {
                     if ui.button("Send Page").clicked() {
                         self.tab = Tab::Send;
@@ -71,24 +165,64 @@ impl eframe::App for View {
                     if ui.button("Receive Page").clicked() {
                         self.tab = Tab::Receive;
                     }
+                    if ui.button("Settings").clicked() {
+                        self.tab = Tab::Settings;
+                        self.reload_identities();
+                    }
                 });
             }
             match self.tab {
                 Tab::Send => {
-                    self.show_send_ui(ctx,ui);
+                    self.show_send_ui(ctx, ui);
                 }
                 Tab::Receive => {
                     self.show_receive_ui(ui);
                 }
+                Tab::Settings => {
+                    self.show_settings_ui(ui);
+                }
             }
             self.show_results(ui);
         });
     }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        if let Some(shares) = self.shares.get() {
+            if let Err(e) = self.tokio_runtime.block_on(shares.shutdown()) {
+                eprintln!("failed to stop the shares: {e:#}");
+            }
+        }
+    }
 }
 
 impl View {
     fn init(&mut self, ctx: &Context) {
         ctx.set_pixels_per_point(2.0);
+        #[cfg(unix)]
+        {
+            let client = Client::new(default_socket_path());
+            if self.tokio_runtime.block_on(client.list_shares()).is_ok() {
+                self.shares.set(Shares::Daemon(client)).ok();
+            }
+        }
+        self.scan_leftovers();
+        let shares = self.shares.clone();
+        let share_list = self.share_list.clone();
+        let share_error = self.share_error.clone();
+        self.tokio_runtime.spawn(async move {
+            // serve the shares of the last run again right away
+            if let Err(e) = local_shares(&shares, &share_error).await {
+                share_error.send_replace(Some(format!("{e:#}")));
+            }
+            loop {
+                if let Some(shares) = shares.get() {
+                    if let Ok(list) = shares.list().await {
+                        share_list.send_replace(list);
+                    }
+                }
+                tokio::time::sleep(SHARE_REFRESH).await;
+            }
+        });
         self.init = false;
     }
 
@@ -110,7 +244,9 @@ impl View {
                     HumanBytes(view_progress.bytes_per_second)
                 ));
             }
-            ViewUpdate::DownloadDone{stats, path} => {
+            // imports show their progress in the send tab
+            ViewUpdate::Import(_) => {}
+            ViewUpdate::DownloadDone { stats, path } => {
                 ui.label(format!(
                     "Transferred {} in {}, {}/s",
                     HumanBytes(stats.bytes_read),
@@ -131,48 +267,460 @@ impl View {
         }
     }
 
-    fn show_send_ui(&mut self,ctx: &Context, ui: &mut Ui) {
-        if let Some(handle) = &self.sending_handle {
-            if handle.is_finished() {
-                self.sending_handle = None;
-            } else {
-                if ui.button("Cancel").clicked() {
-                    self.cancel_sender.send(true).unwrap();
+    fn show_send_ui(&mut self, ctx: &Context, ui: &mut Ui) {
+        ui.label("Insert path to your file or directory");
+        egui::TextEdit::multiline(&mut self.path)
+            .hint_text("C:\\...   or \"C:\\...\"")
+            .show(ui);
+        let clean_path = remove_quotes(&self.path);
+        self.path = clean_path.into();
+
+        ui.horizontal(|ui| {
+            if ui.button("Add").clicked() {
+                self.stage_path();
+            }
+            if ui.button("Pick files").clicked() {
+                if let Some(paths) = rfd::FileDialog::new().pick_files() {
+                    self.stage(paths);
                 }
             }
-        } else {
-            ui.label("Insert path to your file or directory");
-            egui::TextEdit::multiline(&mut self.path)
-                .hint_text("C:\\...   or \"C:\\...\"")
-                .show(ui);
-            let clean_path = remove_quotes(&self.path);
-            self.path = clean_path.into();
+            if ui.button("Pick folders").clicked() {
+                if let Some(paths) = rfd::FileDialog::new().pick_folders() {
+                    self.stage(paths);
+                }
+            }
+        });
+
+        let dropped = ctx.input(|i| {
+            i.raw
+                .dropped_files
+                .iter()
+                .filter_map(|file| file.path.clone())
+                .collect::<Vec<_>>()
+        });
+        self.stage(dropped);
+        self.show_staged(ui);
+        self.show_import_options(ui);
+        ui.horizontal(|ui| {
+            ui.label("Only for");
+            ui.text_edit_singleline(&mut self.allow).on_hover_text(
+                "Node ids of the receivers, separated by spaces. \
+                     Everyone else is refused, even with the ticket",
+            );
+        });
+        ui.checkbox(&mut self.ask, "Ask before each download")
+            .on_hover_text("New peers wait until you allow or deny them below the share");
+        ui.collapsing("Limits", |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Stop after downloads");
+                ui.text_edit_singleline(&mut self.max_downloads);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Stop after");
+                ui.text_edit_singleline(&mut self.expire_after)
+                    .on_hover_text("A duration like 30m or 2h");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Stop when idle for");
+                ui.text_edit_singleline(&mut self.idle_timeout)
+                    .on_hover_text("A duration like 10m");
+            });
+        });
+
+        #[cfg(unix)]
+        if matches!(self.shares.get(), Some(Shares::Daemon(_))) {
+            ui.label("Shares keep running in the background daemon after closing the window.");
+        }
+        if matches!(self.shares.get(), Some(Shares::Local(_))) {
+            ui.label("Shares are served again the next time sendme starts, until stopped.");
+        }
+
+        if ui.button("Send").clicked() {
+            self.stage_path();
+            let limits = self
+                .access_options()
+                .and_then(|access| Ok((access, self.expiry_options()?)));
+            match limits {
+                Ok((access, expiry)) if !self.staged.is_empty() => {
+                    let options = self.import_options();
+                    let paths = std::mem::take(&mut self.staged);
+                    self.add_share(paths, options, access, expiry);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.share_error.send_replace(Some(format!("{e:#}")));
+                }
+            }
+        }
+        self.show_imports(ui);
+        if let Some(error) = self.share_error.borrow().as_ref() {
+            ui.colored_label(Color32::RED, error);
+        }
+        ui.separator();
+        self.show_shares(ui);
+    }
 
-            ctx.input(|i| {
-                for file in &i.raw.dropped_files {
-                    if let Some(path) = &file.path {
-                        self.path = path.to_str().unwrap().into();
+    fn show_imports(&mut self, ui: &mut Ui) {
+        self.imports.retain(|import| !import.task.is_finished());
+        let mut cancelled = None;
+        for (index, import) in self.imports.iter().enumerate() {
+            ui.group(|ui| {
+                let name = match import.paths.as_slice() {
+                    [path] => path.display().to_string(),
+                    paths => format!("{} paths", paths.len()),
+                };
+                ui.label(format!("Importing {name}"));
+                match import.progress.borrow().deref() {
+                    ViewUpdate::Import(progress) => {
+                        let hashed = progress.hashed as f32 / progress.size.max(1) as f32;
+                        ui.add(ProgressBar::new(hashed));
+                        ui.label(format!(
+                            "{} file(s), {}/{}  {}/s",
+                            progress.files,
+                            HumanBytes(progress.hashed),
+                            HumanBytes(progress.size),
+                            HumanBytes(progress.bytes_per_second)
+                        ));
+                        ui.label(&progress.current);
                     }
+                    // a daemon imports without reporting progress
+                    _ => {
+                        ui.spinner();
+                    }
+                }
+                if ui.button("Cancel").clicked() {
+                    cancelled = Some(index);
                 }
             });
-            
-            if ui.button("Send").clicked() {
-                self.cancel_sender.send(false).unwrap();
-                let args = SendArgs {
-                    path: PathBuf::from(self.path.clone()),
-                    common: CommonArgs::default(),
-                    ticket_type: AddrInfoOptions::default(),
-                };
-                let sender = self.sender.clone();
-                let cancel_receiver = self.cancel_receiver.clone();
-                let task = self
-                    .tokio_runtime
-                    .spawn(async move { send(args, sender, cancel_receiver).await });
-                self.sending_handle = Some(task);
+        }
+        if let Some(index) = cancelled {
+            // the import cleans up after itself when it is dropped, a daemon when
+            // the request is dropped with it
+            self.imports.remove(index).task.abort();
+        }
+    }
+
+    fn show_shares(&mut self, ui: &mut Ui) {
+        let shares = self.share_list.borrow().clone();
+        if shares.is_empty() {
+            ui.label("Nothing shared yet");
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for share in shares {
+                ui.group(|ui| {
+                    for path in &share.paths {
+                        ui.label(path.display().to_string());
+                    }
+                    for warning in &share.warnings {
+                        ui.colored_label(Color32::YELLOW, warning);
+                    }
+                    for path in &share.stale {
+                        ui.colored_label(
+                            Color32::YELLOW,
+                            format!("{} changed and is no longer served", path.display()),
+                        );
+                    }
+                    let size = match share.compressed_size {
+                        Some(compressed) => format!(
+                            "{} ({} compressed)",
+                            HumanBytes(share.size),
+                            HumanBytes(compressed)
+                        ),
+                        None => HumanBytes(share.size).to_string(),
+                    };
+                    ui.label(format!(
+                        "{size} in {} file(s), started {} ago, {} uploaded",
+                        share.files,
+                        HumanDuration(Duration::from_secs(now.saturating_sub(share.started))),
+                        HumanBytes(share.uploaded)
+                    ));
+                    if !share.allowed.is_empty() {
+                        ui.label(format!("only for {} node(s)", share.allowed.len()));
+                    }
+                    if let Some(max) = share.expiry.max_downloads {
+                        ui.label(format!("{} of {max} downloads", share.downloads));
+                    }
+                    if let Some(expires) = share.expires {
+                        let left = Duration::from_secs(expires.saturating_sub(now));
+                        ui.label(format!("stops in {}", HumanDuration(left)));
+                    }
+                    for peer in &share.peers {
+                        let state = match peer.state {
+                            UploadState::Transferring => "downloading",
+                            UploadState::Done => "done",
+                            UploadState::Aborted => "aborted",
+                            UploadState::Refused => {
+                                ui.colored_label(
+                                    Color32::RED,
+                                    format!("refused {}, it is not allowed", peer.node_id),
+                                );
+                                continue;
+                            }
+                            UploadState::Pending => {
+                                ui.label(format!("{} wants to download", peer.node_id));
+                                ui.horizontal(|ui| {
+                                    for (text, approval) in [
+                                        ("Allow once", Approval::Once),
+                                        ("Always allow", Approval::Always),
+                                        ("Deny", Approval::Deny),
+                                    ] {
+                                        if ui.button(text).clicked() {
+                                            self.decide(share.id, peer.node_id, approval);
+                                        }
+                                    }
+                                });
+                                continue;
+                            }
+                        };
+                        // peers download the compressed blobs
+                        let size = share.compressed_size.unwrap_or(share.size);
+                        let progress = peer.bytes as f32 / size.max(1) as f32;
+                        ui.horizontal(|ui| {
+                            ui.label(peer.node_id.fmt_short());
+                            ui.add(ProgressBar::new(progress).desired_width(120.0));
+                            ui.label(format!(
+                                "{} blobs, {} {state}",
+                                peer.blobs,
+                                HumanBytes(peer.bytes)
+                            ));
+                        });
+                    }
+                    Self::show_ticket(ui, &share.ticket);
+                    if ui.button("Stop").clicked() {
+                        self.stop_share(share.id);
+                    }
+                });
             }
+        });
+    }
+
+    /// Move the path typed into the text field to the staged paths.
+    fn stage_path(&mut self) {
+        if !self.path.is_empty() {
+            let path = PathBuf::from(std::mem::take(&mut self.path));
+            self.stage(vec![path]);
+        }
+    }
+
+    fn stage(&mut self, paths: Vec<PathBuf>) {
+        for path in paths {
+            if !self.staged.contains(&path) {
+                self.staged.push(path);
+            }
+        }
+    }
+
+    fn show_staged(&mut self, ui: &mut Ui) {
+        let mut remove = None;
+        for (index, path) in self.staged.iter().enumerate() {
+            ui.horizontal(|ui| {
+                if ui.small_button("x").clicked() {
+                    remove = Some(index);
+                }
+                ui.label(path.display().to_string());
+            });
+        }
+        if let Some(index) = remove {
+            self.staged.remove(index);
+        }
+    }
+
+    fn show_import_options(&mut self, ui: &mut Ui) {
+        ui.collapsing("Filters", |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Include");
+                ui.text_edit_singleline(&mut self.include)
+                    .on_hover_text("Globs like *.rs or docs/, separated by spaces");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Exclude");
+                ui.text_edit_singleline(&mut self.exclude)
+                    .on_hover_text("Globs like target/ or *.log, separated by spaces");
+            });
+            ui.checkbox(&mut self.gitignore, "Respect .gitignore");
+            ui.checkbox(&mut self.include_hidden, "Include hidden files");
+            egui::ComboBox::from_label("Symbolic links")
+                .selected_text(self.symlinks.to_string())
+                .show_ui(ui, |ui| {
+                    for policy in [
+                        SymlinkPolicy::Skip,
+                        SymlinkPolicy::Follow,
+                        SymlinkPolicy::Preserve,
+                    ] {
+                        ui.selectable_value(&mut self.symlinks, policy, policy.to_string());
+                    }
+                });
+            ui.checkbox(
+                &mut self.preserve_metadata,
+                "Keep empty folders, permissions and dates",
+            )
+            .on_hover_text("Older versions of sendme receive an extra .sendme-metadata.json file");
+        });
+        ui.checkbox(&mut self.compress, "Compress")
+            .on_hover_text("Saves time on slow connections for text, logs and source code");
+        ui.checkbox(&mut self.pack, "Pack small files")
+            .on_hover_text("Much faster for folders with thousands of tiny files");
+        ui.checkbox(&mut self.snapshot, "Copy files when sharing")
+            .on_hover_text(
+                "Receivers get the files as they are now, even if you change them later. \
+                 Takes as much disk space as the files",
+            );
+        if self.staged.is_empty() {
+            return;
+        }
+        let previewed = (self.staged.clone(), self.import_options());
+        if self.previewed.as_ref() != Some(&previewed) {
+            self.update_preview(previewed.0.clone(), previewed.1.clone());
+            self.previewed = Some(previewed);
+        }
+        if let Some(preview) = self.preview.borrow().as_ref() {
+            ui.label(preview);
         }
     }
 
+    fn import_options(&self) -> ImportOptions {
+        let globs = |s: &str| s.split_whitespace().map(str::to_string).collect();
+        ImportOptions {
+            include: globs(&self.include),
+            exclude: globs(&self.exclude),
+            gitignore: self.gitignore,
+            skip_hidden: !self.include_hidden,
+            symlinks: self.symlinks,
+            preserve_metadata: self.preserve_metadata,
+            compress: self.compress,
+            pack: self.pack,
+            import_mode: if self.snapshot {
+                ImportModeOption::Snapshot
+            } else {
+                ImportModeOption::Reference
+            },
+        }
+    }
+
+    fn access_options(&self) -> anyhow::Result<AccessOptions> {
+        let allow = self
+            .allow
+            .split_whitespace()
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .context("invalid node id")?;
+        Ok(AccessOptions {
+            allow,
+            ask: self.ask,
+        })
+    }
+
+    fn expiry_options(&self) -> anyhow::Result<ExpiryOptions> {
+        let duration = |s: &str| {
+            let s = s.trim();
+            (!s.is_empty())
+                .then(|| humantime::parse_duration(s))
+                .transpose()
+                .with_context(|| format!("invalid duration {s}"))
+        };
+        let max_downloads = self.max_downloads.trim();
+        let max_downloads = (!max_downloads.is_empty())
+            .then(|| max_downloads.parse())
+            .transpose()
+            .context("invalid number of downloads")?;
+        Ok(ExpiryOptions {
+            max_downloads,
+            expire_after: duration(&self.expire_after)?,
+            idle_timeout: duration(&self.idle_timeout)?,
+        })
+    }
+
+    /// Count the files that would be shared in the background.
+    fn update_preview(&self, paths: Vec<PathBuf>, options: ImportOptions) {
+        let preview = self.preview.clone();
+        preview.send_replace(Some("Counting files...".to_string()));
+        // only the newest preview is shown, older walks may finish later
+        let generation = self.preview_generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let preview_generation = self.preview_generation.clone();
+        self.tokio_runtime.spawn_blocking(move || {
+            let text = match send::preview(paths, &options) {
+                Ok((files, size)) => format!("{files} file(s), {}", HumanBytes(size)),
+                Err(e) => format!("{e:#}"),
+            };
+            if preview_generation.load(Ordering::Relaxed) == generation {
+                preview.send_replace(Some(text));
+            }
+        });
+    }
+
+    /// Import and serve paths in one ticket in the background, starting the
+    /// local endpoint if needed.
+    fn add_share(
+        &mut self,
+        paths: Vec<PathBuf>,
+        options: ImportOptions,
+        access: AccessOptions,
+        expiry: ExpiryOptions,
+    ) {
+        let shares = self.shares.clone();
+        let share_list = self.share_list.clone();
+        let share_error = self.share_error.clone();
+        let (progress_sender, progress) = channel(ViewUpdate::Nothing);
+        let reporter = Reporter::new().view(progress_sender);
+        let import_paths = paths.clone();
+        let task = self.tokio_runtime.spawn(async move {
+            let res = async {
+                let shares = local_shares(&shares, &share_error).await?;
+                shares
+                    .add(paths, &options, &access, &expiry, reporter)
+                    .await?;
+                share_list.send_replace(shares.list().await?);
+                anyhow::Ok(())
+            }
+            .await;
+            share_error.send_replace(res.err().map(|e| format!("{e:#}")));
+        });
+        self.imports.push(Import {
+            paths: import_paths,
+            task,
+            progress,
+        });
+    }
+
+    fn decide(&self, id: ShareId, node_id: NodeId, approval: Approval) {
+        let Some(shares) = self.shares.get().cloned() else {
+            return;
+        };
+        let share_list = self.share_list.clone();
+        let share_error = self.share_error.clone();
+        self.tokio_runtime.spawn(async move {
+            let res = async {
+                shares.decide(id, node_id, approval).await?;
+                share_list.send_replace(shares.list().await?);
+                anyhow::Ok(())
+            }
+            .await;
+            share_error.send_replace(res.err().map(|e| format!("{e:#}")));
+        });
+    }
+
+    fn stop_share(&self, id: ShareId) {
+        let Some(shares) = self.shares.get().cloned() else {
+            return;
+        };
+        let share_list = self.share_list.clone();
+        let share_error = self.share_error.clone();
+        self.tokio_runtime.spawn(async move {
+            let res = async {
+                shares.stop(id).await?;
+                share_list.send_replace(shares.list().await?);
+                anyhow::Ok(())
+            }
+            .await;
+            share_error.send_replace(res.err().map(|e| format!("{e:#}")));
+        });
+    }
+
     fn show_receive_ui(&mut self, ui: &mut Ui) {
         if let Some(handle) = &self.receiving_handle {
             if handle.is_finished() {
@@ -180,30 +728,245 @@ impl View {
             }
         } else {
             ui.label("Insert ticket to receive data.");
-            egui::TextEdit::multiline(&mut self.ticket)
+            let edit = egui::TextEdit::multiline(&mut self.ticket)
                 .hint_text("blobabcdefg....")
                 .show(ui);
+            if edit.response.changed() {
+                self.ticket_error = None;
+            }
+            if let Some(error) = &self.ticket_error {
+                ui.colored_label(Color32::RED, error);
+            }
+            let downloads = dirs::download_dir();
+            if downloads.is_some() {
+                ui.checkbox(&mut self.save_to_downloads, "Always save to Downloads");
+            }
 
             if ui.button("Receive").clicked() {
-                let args = ReceiveArgs {
-                    common: CommonArgs::default(),
-                    ticket: BlobTicket::from_str(&self.ticket).unwrap(),
+                let ticket = match BlobTicket::from_str(remove_quotes(self.ticket.trim())) {
+                    Ok(ticket) => ticket,
+                    Err(e) => {
+                        self.ticket_error = Some(format!("invalid ticket: {e}"));
+                        return;
+                    }
+                };
+                let target = match downloads {
+                    Some(dir) if self.save_to_downloads => ExportTarget::Dir(dir),
+                    _ => ExportTarget::Dialog,
                 };
+                let _guard = self.tokio_runtime.enter();
+                let handle = ReceiveSession::new(ticket)
+                    .target(target)
+                    .view(self.sender.clone())
+                    .spawn();
+                self.receiving_handle = Some(handle);
+            }
+        }
+    }
+
+    fn show_settings_ui(&mut self, ui: &mut Ui) {
+        ui.heading("Identity");
+        ui.label("Receivers see this node id. Running shares keep theirs until restarted.");
+        if std::env::var_os("IROH_SECRET").is_some() {
+            ui.colored_label(Color32::YELLOW, "IROH_SECRET is set and used instead");
+        }
+        let mut action: Option<Box<dyn FnOnce() -> anyhow::Result<()>>> = None;
+        for entry in &self.identities {
+            ui.group(|ui| {
+                let name = entry.name.clone();
+                if entry.active {
+                    ui.strong(format!("{name} (in use)"));
+                } else {
+                    ui.label(&name);
+                }
+                ui.horizontal(|ui| {
+                    ui.monospace(entry.node_id.to_string());
+                    if ui.small_button("Copy").clicked() {
+                        action = Some(Box::new({
+                            let node_id = entry.node_id.to_string();
+                            move || copy(node_id)
+                        }));
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if !entry.active && ui.button("Use").clicked() {
+                        let name = name.clone();
+                        action = Some(Box::new(move || identity::set_active(&name)));
+                    }
+                    if ui
+                        .button("Rotate")
+                        .on_hover_text("Peers that only allow the old node id will refuse you")
+                        .clicked()
+                    {
+                        let name = name.clone();
+                        action = Some(Box::new(move || identity::rotate(&name).map(drop)));
+                    }
+                    if ui
+                        .button("Export")
+                        .on_hover_text("Copies the secret key, keep it private")
+                        .clicked()
+                    {
+                        let name = name.clone();
+                        action = Some(Box::new(move || copy(identity::export(&name)?)));
+                    }
+                    if !entry.active && ui.button("Remove").clicked() {
+                        action = Some(Box::new(move || identity::remove(&name)));
+                    }
+                });
+            });
+        }
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Name");
+            ui.text_edit_singleline(&mut self.identity_name);
+        });
+        ui.horizontal(|ui| {
+            ui.label("Secret key");
+            ui.add(egui::TextEdit::singleline(&mut self.identity_secret).password(true))
+                .on_hover_text("Leave empty to create a new key");
+        });
+        if ui.button("Add identity").clicked() {
+            let name = std::mem::take(&mut self.identity_name);
+            let secret = std::mem::take(&mut self.identity_secret);
+            action = Some(Box::new(move || {
+                if secret.trim().is_empty() {
+                    identity::create(&name).map(drop)
+                } else {
+                    identity::import(&name, &secret).map(drop)
+                }
+            }));
+        }
+        if let Some(action) = action {
+            self.identity_error = action().err().map(|e| format!("{e:#}"));
+            self.reload_identities();
+        }
+        if let Some(error) = &self.identity_error {
+            ui.colored_label(Color32::RED, error);
+        }
+        ui.separator();
+        self.show_leftovers(ui);
+    }
 
-                let handle = self.tokio_runtime.handle().clone();
-                let sender = self.sender.clone();
-                let task = self.tokio_runtime.spawn_blocking(move || {
-                    handle.block_on(async {
-                        receive(args, sender).await.unwrap();
-                    })
+    /// Point at the leftovers from the other tabs.
+    fn show_leftover_notice(&mut self, ui: &mut Ui) {
+        if matches!(self.tab, Tab::Settings) {
+            return;
+        }
+        let leftovers = self.leftovers.borrow();
+        if leftovers.is_empty() {
+            return;
+        }
+        let size = leftovers.iter().map(|leftover| leftover.size).sum::<u64>();
+        let text = format!(
+            "Interrupted transfers left {} store(s) behind, {}",
+            leftovers.len(),
+            HumanBytes(size)
+        );
+        drop(leftovers);
+        ui.horizontal(|ui| {
+            ui.colored_label(Color32::YELLOW, text);
+            if ui.button("Review").clicked() {
+                self.tab = Tab::Settings;
+                self.reload_identities();
+            }
+        });
+    }
+
+    fn show_leftovers(&mut self, ui: &mut Ui) {
+        ui.heading("Left behind");
+        let leftovers = self.leftovers.borrow().clone();
+        if leftovers.is_empty() {
+            ui.label("No interrupted transfers");
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        for leftover in leftovers {
+            ui.group(|ui| {
+                let kind = match leftover.kind {
+                    LeftoverKind::Send => "Upload",
+                    LeftoverKind::Receive => "Download",
+                };
+                let age = Duration::from_secs(now.saturating_sub(leftover.modified));
+                ui.label(format!(
+                    "{kind} store, {}, last written {} ago",
+                    HumanBytes(leftover.size),
+                    HumanDuration(age)
+                ));
+                ui.label(leftover.path.display().to_string());
+                ui.horizontal(|ui| {
+                    if let Some(ticket) = &leftover.ticket {
+                        if ui
+                            .button("Resume")
+                            .on_hover_text("Download the rest, the store is used again")
+                            .clicked()
+                        {
+                            self.ticket = ticket.to_string();
+                            self.tab = Tab::Receive;
+                        }
+                    }
+                    if ui.button("Delete").clicked() {
+                        self.leftover_error = scratch::remove_leftover(None, &leftover.path)
+                            .err()
+                            .map(|e| format!("{e:#}"));
+                        self.scan_leftovers();
+                    }
                 });
+            });
+        }
+        if let Some(error) = &self.leftover_error {
+            ui.colored_label(Color32::RED, error);
+        }
+    }
 
-                self.receiving_handle = Some(task);
+    /// Look for leftovers in the background.
+    fn scan_leftovers(&self) {
+        let leftovers = self.leftovers.clone();
+        self.tokio_runtime.spawn_blocking(move || {
+            // a store that can't be read is not worth an error at startup
+            if let Ok(found) = scratch::leftovers(None) {
+                leftovers.send_replace(found);
             }
+        });
+    }
+
+    fn reload_identities(&mut self) {
+        // make sure the active identity shows up even before its first use
+        let res = identity::active()
+            .and_then(|name| identity::load(&name))
+            .and_then(|_| identity::list());
+        match res {
+            Ok(identities) => self.identities = identities,
+            Err(e) => self.identity_error = Some(format!("{e:#}")),
         }
     }
 }
 
+/// The shares, served from this process if no daemon was found, starting with
+/// the ones of the last run.
+async fn local_shares<'a>(
+    shares: &'a OnceCell<Shares>,
+    share_error: &Sender<Option<String>>,
+) -> anyhow::Result<&'a Shares> {
+    shares
+        .get_or_try_init(|| async {
+            let (shares, warnings) =
+                Shares::persistent(&CommonArgs::default(), AddrInfoOptions::default()).await?;
+            if !warnings.is_empty() {
+                share_error.send_replace(Some(warnings.join("\n")));
+            }
+            anyhow::Ok(shares)
+        })
+        .await
+}
+
+fn copy(text: String) -> anyhow::Result<()> {
+    let mut clipboard = Clipboard::new()?;
+    clipboard.set_text(text)?;
+    Ok(())
+}
+
 fn remove_quotes(s: &str) -> &str {
     s.strip_prefix('"')
         .and_then(|s| s.strip_suffix('"'))
@@ -1,5 +1,6 @@
 use std::{
-    io::{self, Read},
+    ffi::OsString,
+    io::{self, Read, Write},
     path::{Path, PathBuf},
     str::FromStr,
 };
@@ -35,6 +36,67 @@ fn read_ascii_lines(mut n: usize, reader: &mut impl Read) -> io::Result<Vec<u8>>
     Ok(res)
 }
 
+/// An environment that keeps sendme in `home`, away from the data and config
+/// directories of whoever runs the tests.
+fn home_env(home: &Path) -> [(&'static str, PathBuf); 4] {
+    [
+        ("HOME", home.to_path_buf()),
+        ("XDG_CONFIG_HOME", home.join("config")),
+        ("XDG_DATA_HOME", home.join("data")),
+        ("SENDME_DATA_DIR", home.join("sendme")),
+    ]
+}
+
+/// Run sendme with `args` in the environment of [`home_env`].
+fn sendme<T: Into<OsString>>(args: impl IntoIterator<Item = T>, home: &Path) -> duct::Expression {
+    let mut cmd = duct::cmd(sendme_bin(), args);
+    for (name, value) in home_env(home) {
+        cmd = cmd.env(name, value);
+    }
+    cmd.env_remove("RUST_LOG") // disable tracing
+}
+
+/// A running `sendme send`, which is killed when it is dropped.
+struct Sender {
+    process: duct::ReaderHandle,
+    /// Everything it printed up to and including the ticket.
+    output: String,
+    ticket: BlobTicket,
+    _home: tempfile::TempDir,
+}
+
+/// Start `sendme send` with `args` in `dir` and wait for its ticket.
+fn send_ticket(dir: &Path, args: &[&str]) -> Sender {
+    let home = tempfile::tempdir().unwrap();
+    let mut process = sendme(["send"].iter().chain(args), home.path())
+        .dir(dir)
+        .stderr_to_stdout()
+        .reader()
+        .unwrap();
+    let output = read_ascii_lines(3, &mut process).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let ticket = output.split_ascii_whitespace().last().unwrap();
+    let ticket = BlobTicket::from_str(ticket).unwrap();
+    Sender {
+        process,
+        output,
+        ticket,
+        _home: home,
+    }
+}
+
+/// Run `sendme receive` for `ticket` in `dir`, returning what it printed.
+///
+/// Fails the test if the download fails.
+fn receive(ticket: &BlobTicket, dir: &Path) -> String {
+    let home = tempfile::tempdir().unwrap();
+    sendme(["receive", &ticket.to_string()], home.path())
+        .dir(dir)
+        .stderr_to_stdout()
+        .read()
+        .unwrap()
+}
+
 // fn wait2() -> Arc<Barrier> {
 //     Arc::new(Barrier::new(2))
 // }
@@ -53,26 +115,8 @@ fn send_recv_file() {
     let tgt_dir = tempfile::tempdir().unwrap();
     let src_file = src_dir.path().join(name);
     std::fs::write(&src_file, &data).unwrap();
-    let mut send_cmd = duct::cmd(
-        sendme_bin(),
-        ["send", src_file.as_os_str().to_str().unwrap()],
-    )
-    .dir(src_dir.path())
-    .env_remove("RUST_LOG") // disable tracing
-    .stderr_to_stdout()
-    .reader()
-    .unwrap();
-    let output = read_ascii_lines(3, &mut send_cmd).unwrap();
-    let output = String::from_utf8(output).unwrap();
-    let ticket = output.split_ascii_whitespace().last().unwrap();
-    let ticket = BlobTicket::from_str(ticket).unwrap();
-    let receive_output = duct::cmd(sendme_bin(), ["receive", &ticket.to_string()])
-        .dir(tgt_dir.path())
-        .env_remove("RUST_LOG") // disable tracing
-        .stderr_to_stdout()
-        .run()
-        .unwrap();
-    assert!(receive_output.status.success());
+    let sender = send_ticket(src_dir.path(), &[src_file.as_os_str().to_str().unwrap()]);
+    receive(&sender.ticket, tgt_dir.path());
     let tgt_file = tgt_dir.path().join(name);
     let tgt_data = std::fs::read(tgt_file).unwrap();
     assert_eq!(tgt_data, data);
@@ -105,34 +149,950 @@ fn send_recv_dir() {
             }
         }
     }
-    let mut send_cmd = duct::cmd(
-        sendme_bin(),
-        ["send", src_data_dir.as_os_str().to_str().unwrap()],
+    let sender = send_ticket(
+        src_dir.path(),
+        &[src_data_dir.as_os_str().to_str().unwrap()],
+    );
+    receive(&sender.ticket, tgt_dir.path());
+    // validate directory structure
+    for i in 0..5 {
+        for j in 0..5 {
+            for k in 0..5 {
+                let (name, data) = create_file(&tgt_data_dir, i, j, k);
+                let tgt_data = std::fs::read(&name).unwrap();
+                assert_eq!(tgt_data, data);
+            }
+        }
+    }
+}
+
+#[test]
+fn send_recv_file_target_dir() {
+    let home = tempfile::tempdir().unwrap();
+    let name = "somefile.bin";
+    let data = vec![1u8; 100];
+    // create src and tgt dir, and src file
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join(name);
+    std::fs::write(&src_file, &data).unwrap();
+    let sender = send_ticket(src_dir.path(), &[src_file.as_os_str().to_str().unwrap()]);
+    // the target directory does not exist yet and is not the working directory
+    let export_dir = tgt_dir.path().join("nested").join("export");
+    let receive_output = sendme(
+        [
+            "receive",
+            &sender.ticket.to_string(),
+            "--target-dir",
+            export_dir.as_os_str().to_str().unwrap(),
+        ],
+        home.path(),
     )
     .dir(src_dir.path())
-    .env_remove("RUST_LOG") // disable tracing
     .stderr_to_stdout()
-    .reader()
+    .run()
     .unwrap();
-    let output = read_ascii_lines(3, &mut send_cmd).unwrap();
+    assert!(receive_output.status.success());
+    let tgt_data = std::fs::read(export_dir.join(name)).unwrap();
+    assert_eq!(tgt_data, data);
+    sender.process.kill().unwrap();
+}
+
+#[test]
+fn send_recv_multiple_paths() {
+    // two files with the same name from different directories, and a directory
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let first = src_dir.path().join("a").join("same.txt");
+    let second = src_dir.path().join("b").join("same.txt");
+    let nested = src_dir.path().join("c").join("nested.bin");
+    for (i, file) in [&first, &second, &nested].into_iter().enumerate() {
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(file, vec![i as u8; 100]).unwrap();
+    }
+    let paths = [&first, &second, &nested.parent().unwrap().to_path_buf()]
+        .map(|path| path.as_os_str().to_str().unwrap().to_string());
+    let paths = paths.iter().map(String::as_str).collect::<Vec<_>>();
+    let sender = send_ticket(src_dir.path(), &paths);
+    receive(&sender.ticket, tgt_dir.path());
+    let read = |name: &str| std::fs::read(tgt_dir.path().join(name)).unwrap();
+    assert_eq!(read("same.txt"), vec![0u8; 100]);
+    assert_eq!(read("same (2).txt"), vec![1u8; 100]);
+    assert_eq!(read("c/nested.bin"), vec![2u8; 100]);
+}
+
+#[test]
+fn send_recv_filters() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let src_data_dir = src_dir.path().join("data");
+    for name in [
+        "src/main.rs",
+        "target/out.bin",
+        "notes.log",
+        ".hidden",
+        ".gitignore",
+    ] {
+        let path = src_data_dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, name).unwrap();
+    }
+    std::fs::write(src_data_dir.join(".gitignore"), "target/\n").unwrap();
+    let sender = send_ticket(
+        src_dir.path(),
+        &[
+            src_data_dir.as_os_str().to_str().unwrap(),
+            "--gitignore",
+            "--skip-hidden",
+            "--exclude",
+            "*.log",
+        ],
+    );
+    receive(&sender.ticket, tgt_dir.path());
+    let tgt_data_dir = tgt_dir.path().join("data");
+    assert!(tgt_data_dir.join("src/main.rs").exists());
+    for name in ["target/out.bin", "notes.log", ".hidden", ".gitignore"] {
+        assert!(!tgt_data_dir.join(name).exists(), "{name} was shared");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn send_recv_preserved_symlinks() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let src_data_dir = src_dir.path().join("data");
+    std::fs::create_dir_all(src_data_dir.join("sub")).unwrap();
+    std::fs::write(src_data_dir.join("real.txt"), "real").unwrap();
+    std::os::unix::fs::symlink("../real.txt", src_data_dir.join("sub/inside")).unwrap();
+    std::os::unix::fs::symlink("../../outside", src_data_dir.join("sub/outside")).unwrap();
+    let sender = send_ticket(
+        src_dir.path(),
+        &[
+            src_data_dir.as_os_str().to_str().unwrap(),
+            "--symlinks",
+            "preserve",
+        ],
+    );
+    receive(&sender.ticket, tgt_dir.path());
+    let tgt_data_dir = tgt_dir.path().join("data");
+    let inside = tgt_data_dir.join("sub/inside");
+    assert_eq!(
+        std::fs::read_link(&inside).unwrap(),
+        Path::new("../real.txt")
+    );
+    assert_eq!(std::fs::read(&inside).unwrap(), b"real");
+    // links out of the download are not recreated
+    assert!(std::fs::symlink_metadata(tgt_data_dir.join("sub/outside")).is_err());
+    assert!(!tgt_data_dir.join(".sendme-metadata.json").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn send_recv_chained_symlinks() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let src_data_dir = src_dir.path().join("data");
+    std::fs::create_dir_all(src_data_dir.join("x")).unwrap();
+    // each looks inside on its own, together they lead out of the download
+    std::os::unix::fs::symlink("..", src_data_dir.join("x/dl")).unwrap();
+    std::os::unix::fs::symlink("x/dl/../..", src_data_dir.join("s")).unwrap();
+    let sender = send_ticket(
+        src_dir.path(),
+        &[
+            src_data_dir.as_os_str().to_str().unwrap(),
+            "--symlinks",
+            "preserve",
+            "--preserve-metadata",
+        ],
+    );
+    let receive_output = receive(&sender.ticket, tgt_dir.path());
+    let tgt_data_dir = tgt_dir.path().join("data");
+    assert_eq!(
+        std::fs::read_link(tgt_data_dir.join("x/dl")).unwrap(),
+        Path::new("..")
+    );
+    assert!(std::fs::symlink_metadata(tgt_data_dir.join("s")).is_err());
+    assert!(
+        receive_output.contains("not recreating link data/s"),
+        "{receive_output}"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn send_recv_preserved_metadata() {
+    use std::os::unix::fs::PermissionsExt;
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let src_data_dir = src_dir.path().join("data");
+    std::fs::create_dir_all(src_data_dir.join("empty")).unwrap();
+    let script = src_data_dir.join("run.sh");
+    std::fs::write(&script, "#!/bin/sh").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o750)).unwrap();
+    let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(&script)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    let sender = send_ticket(
+        src_dir.path(),
+        &[
+            src_data_dir.as_os_str().to_str().unwrap(),
+            "--preserve-metadata",
+        ],
+    );
+    receive(&sender.ticket, tgt_dir.path());
+    let tgt_data_dir = tgt_dir.path().join("data");
+    assert!(tgt_data_dir.join("empty").is_dir());
+    let metadata = std::fs::metadata(tgt_data_dir.join("run.sh")).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o750);
+    assert_eq!(metadata.modified().unwrap(), modified);
+    assert!(!tgt_data_dir.join(".sendme-metadata.json").exists());
+}
+
+#[test]
+fn send_recv_compressed() {
+    let name = "log.txt";
+    let data = "GET /index.html 200\n".repeat(10_000);
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join(name);
+    std::fs::write(&src_file, &data).unwrap();
+    let sender = send_ticket(
+        src_dir.path(),
+        &[src_file.as_os_str().to_str().unwrap(), "--compress"],
+    );
+    assert!(sender.output.contains("compressed"));
+    receive(&sender.ticket, tgt_dir.path());
+    let tgt_file = tgt_dir.path().join(name);
+    assert_eq!(std::fs::read_to_string(&tgt_file).unwrap(), data);
+    assert!(!tgt_dir.path().join(".sendme-metadata.json").exists());
+}
+
+#[test]
+fn send_recv_allowed_nodes() {
+    let home = tempfile::tempdir().unwrap();
+    let allowed_secret = "01".repeat(32);
+    let allowed = iroh::SecretKey::from_str(&allowed_secret).unwrap().public();
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("secret.txt");
+    std::fs::write(&src_file, "only for one").unwrap();
+    let sender = send_ticket(
+        src_dir.path(),
+        &[
+            src_file.as_os_str().to_str().unwrap(),
+            "--allow",
+            &allowed.to_string(),
+        ],
+    );
+    // anyone else is refused, even with the ticket
+    let refused_output = sendme(["receive", &sender.ticket.to_string()], home.path())
+        .dir(tgt_dir.path())
+        .env("IROH_SECRET", "02".repeat(32))
+        .stderr_to_stdout()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!refused_output.status.success());
+    assert!(!tgt_dir.path().join("secret.txt").exists());
+    let receive_output = sendme(["receive", &sender.ticket.to_string()], home.path())
+        .dir(tgt_dir.path())
+        .env("IROH_SECRET", allowed_secret)
+        .stderr_to_stdout()
+        .run()
+        .unwrap();
+    assert!(receive_output.status.success());
+    let received = std::fs::read_to_string(tgt_dir.path().join("secret.txt")).unwrap();
+    assert_eq!(received, "only for one");
+}
+
+#[test]
+fn send_recv_ask() {
+    let home = tempfile::tempdir().unwrap();
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("asked.txt");
+    std::fs::write(&src_file, "ask first").unwrap();
+    let send_home = tempfile::tempdir().unwrap();
+    let mut sender = std::process::Command::new(sendme_bin())
+        .args(["send", src_file.as_os_str().to_str().unwrap(), "--ask"])
+        .current_dir(src_dir.path())
+        .envs(home_env(send_home.path()))
+        .env_remove("RUST_LOG") // disable tracing
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut answers = sender.stdin.take().unwrap();
+    let mut stdout = sender.stdout.take().unwrap();
+    let output = read_ascii_lines(3, &mut stdout).unwrap();
     let output = String::from_utf8(output).unwrap();
     let ticket = output.split_ascii_whitespace().last().unwrap();
     let ticket = BlobTicket::from_str(ticket).unwrap();
-    let receive_output = duct::cmd(sendme_bin(), ["receive", &ticket.to_string()])
+    let mut receive = |secret: String, answer: &str| {
+        let receiver = sendme(["receive", &ticket.to_string()], home.path())
+            .dir(tgt_dir.path())
+            .env("IROH_SECRET", secret)
+            .stderr_to_stdout()
+            .stdout_capture()
+            .unchecked()
+            .start()
+            .unwrap();
+        // the download is held until the sender answers
+        loop {
+            let line = String::from_utf8(read_ascii_lines(1, &mut stdout).unwrap()).unwrap();
+            assert!(!line.is_empty(), "sender exited");
+            if line.contains("wants to download") {
+                break;
+            }
+        }
+        writeln!(answers, "{answer}").unwrap();
+        receiver.wait().unwrap().status.success()
+    };
+    assert!(!receive("03".repeat(32), "deny"));
+    assert!(!tgt_dir.path().join("asked.txt").exists());
+    assert!(receive("04".repeat(32), "once"));
+    let received = std::fs::read_to_string(tgt_dir.path().join("asked.txt")).unwrap();
+    assert_eq!(received, "ask first");
+    sender.kill().unwrap();
+}
+
+#[test]
+fn send_recv_max_downloads() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("once.txt");
+    std::fs::write(&src_file, "only once").unwrap();
+    let mut sender = send_ticket(
+        src_dir.path(),
+        &[
+            src_file.as_os_str().to_str().unwrap(),
+            "--max-downloads",
+            "1",
+            "--data-dir",
+            data_dir.path().to_str().unwrap(),
+        ],
+    );
+    receive(&sender.ticket, tgt_dir.path());
+    // the sender stops by itself and cleans up
+    let mut rest = String::new();
+    sender.process.read_to_string(&mut rest).unwrap();
+    assert!(rest.contains("the share expired"));
+    let leftovers = std::fs::read_dir(data_dir.path().join("scratch")).map_or(0, |dir| dir.count());
+    assert_eq!(leftovers, 0);
+}
+
+#[test]
+fn send_recv_data_dir() {
+    let home = tempfile::tempdir().unwrap();
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("elsewhere.txt");
+    std::fs::write(&src_file, "no scratch here").unwrap();
+    let data_arg = data_dir.path().to_str().unwrap();
+    let sender = send_ticket(
+        src_dir.path(),
+        &[src_file.to_str().unwrap(), "--data-dir", data_arg],
+    );
+    let ticket = sender.ticket.to_string();
+    // the working directories only hold the data
+    assert_eq!(std::fs::read_dir(src_dir.path()).unwrap().count(), 1);
+    // the sender keeps its store in the hash cache
+    assert!(data_dir.path().join("cache").join("store").is_dir());
+    let scratch = data_dir.path().join("scratch");
+    // a data directory that can't be created is an error, not a crash
+    let unwritable = src_file.join("data");
+    let failed_output = sendme(
+        [
+            "receive",
+            &ticket,
+            "--data-dir",
+            unwritable.to_str().unwrap(),
+        ],
+        home.path(),
+    )
+    .dir(tgt_dir.path())
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert!(!failed_output.status.success());
+    let stderr = String::from_utf8_lossy(&failed_output.stderr);
+    assert!(stderr.contains("can not write to"), "{stderr}");
+    let receive_output = sendme(["receive", &ticket, "--data-dir", data_arg], home.path())
         .dir(tgt_dir.path())
-        .env_remove("RUST_LOG") // disable tracing
         .stderr_to_stdout()
         .run()
         .unwrap();
     assert!(receive_output.status.success());
-    // validate directory structure
-    for i in 0..5 {
-        for j in 0..5 {
-            for k in 0..5 {
-                let (name, data) = create_file(&tgt_data_dir, i, j, k);
-                let tgt_data = std::fs::read(&name).unwrap();
-                assert_eq!(tgt_data, data);
+    assert_eq!(std::fs::read_dir(tgt_dir.path()).unwrap().count(), 1);
+    let received = std::fs::read_to_string(tgt_dir.path().join("elsewhere.txt")).unwrap();
+    assert_eq!(received, "no scratch here");
+    // the receiver cleaned up after itself
+    assert_eq!(std::fs::read_dir(&scratch).unwrap().count(), 0);
+    sender.process.kill().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn daemon_restores_shares() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let socket = data_dir.path().join("sendme.sock");
+    let socket = socket.to_str().unwrap();
+    let src_file = src_dir.path().join("kept.txt");
+    std::fs::write(&src_file, "still here tomorrow").unwrap();
+    let daemon = || {
+        sendme(
+            [
+                "daemon",
+                "--socket",
+                socket,
+                "--ticket-type",
+                "relay-and-addresses",
+            ],
+            data_dir.path(),
+        )
+        .env("IROH_SECRET", "03".repeat(32))
+        .stdout_null()
+        .stderr_null()
+        .start()
+        .unwrap()
+    };
+    let ctl = |args: &[&str]| {
+        let mut last_error = None;
+        // the daemon may still be starting
+        for _ in 0..50 {
+            let output = sendme(
+                ["ctl", "--socket", socket].iter().chain(args),
+                data_dir.path(),
+            )
+            .stdout_capture()
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .unwrap();
+            if output.status.success() {
+                return serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap();
+            }
+            last_error = Some(String::from_utf8_lossy(&output.stderr).to_string());
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        panic!("ctl failed: {last_error:?}");
+    };
+    let first = daemon();
+    let share = ctl(&["add", src_file.to_str().unwrap()]);
+    // killed, not shut down
+    first.kill().unwrap();
+    let second = daemon();
+    let shares = ctl(&["list"]);
+    assert_eq!(shares.as_array().unwrap().len(), 1);
+    assert_eq!(shares[0]["id"], share["id"]);
+    assert_eq!(shares[0]["hash"], share["hash"]);
+    // one process at a time serves the shares
+    let other_socket = data_dir.path().join("other.sock");
+    let busy = sendme(
+        ["daemon", "--socket", other_socket.to_str().unwrap()],
+        data_dir.path(),
+    )
+    .env("IROH_SECRET", "03".repeat(32))
+    .stdout_null()
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert!(!busy.status.success());
+    assert!(String::from_utf8_lossy(&busy.stderr).contains("served by another sendme process"));
+    let ticket = BlobTicket::from_str(shares[0]["ticket"].as_str().unwrap()).unwrap();
+    receive(&ticket, tgt_dir.path());
+    let received = std::fs::read_to_string(tgt_dir.path().join("kept.txt")).unwrap();
+    assert_eq!(received, "still here tomorrow");
+    second.kill().unwrap();
+    // a changed file is not served again
+    std::fs::write(&src_file, "changed overnight").unwrap();
+    let third = daemon();
+    let shares = ctl(&["list"]);
+    assert_eq!(shares[0]["stale"][0], src_file.to_str().unwrap());
+    std::fs::remove_file(tgt_dir.path().join("kept.txt")).unwrap();
+    let ticket = shares[0]["ticket"].as_str().unwrap();
+    let stale_output = sendme(["receive", ticket], data_dir.path())
+        .dir(tgt_dir.path())
+        .stderr_to_stdout()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!stale_output.status.success());
+    assert!(!tgt_dir.path().join("kept.txt").exists());
+    third.kill().unwrap();
+}
+
+/// A running `sendme daemon` with its own home, which is killed when it is dropped.
+#[cfg(unix)]
+struct Daemon {
+    process: duct::Handle,
+    socket: PathBuf,
+    home: tempfile::TempDir,
+}
+
+#[cfg(unix)]
+impl Daemon {
+    fn start() -> Self {
+        let home = tempfile::tempdir().unwrap();
+        let socket = home.path().join("sendme.sock");
+        let process = sendme(
+            ["daemon", "--socket", socket.to_str().unwrap()],
+            home.path(),
+        )
+        .stdout_null()
+        .stderr_null()
+        .start()
+        .unwrap();
+        let daemon = Self {
+            process,
+            socket,
+            home,
+        };
+        // until it listens
+        daemon.ctl(&["list"]);
+        daemon
+    }
+
+    /// Run `sendme ctl` with `args`, retrying while the daemon starts, and
+    /// return what it printed.
+    fn ctl(&self, args: &[&str]) -> serde_json::Value {
+        let socket = self.socket.to_str().unwrap();
+        let mut last_error = None;
+        for _ in 0..50 {
+            let output = sendme(
+                ["ctl", "--socket", socket].iter().chain(args),
+                self.home.path(),
+            )
+            .stdout_capture()
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .unwrap();
+            if output.status.success() {
+                if output.stdout.is_empty() {
+                    return serde_json::Value::Null;
+                }
+                return serde_json::from_slice(&output.stdout).unwrap();
             }
+            last_error = Some(String::from_utf8_lossy(&output.stderr).to_string());
+            std::thread::sleep(std::time::Duration::from_millis(100));
         }
+        panic!("ctl failed: {last_error:?}");
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.process.kill().ok();
     }
 }
+
+#[cfg(unix)]
+#[test]
+fn daemon_add_stop() {
+    let daemon = Daemon::start();
+    let src_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("short-lived.txt");
+    std::fs::write(&src_file, "here for a moment").unwrap();
+    let share = daemon.ctl(&["add", src_file.to_str().unwrap()]);
+    let shares = daemon.ctl(&["list"]);
+    assert_eq!(shares.as_array().unwrap().len(), 1);
+    assert_eq!(shares[0]["id"], share["id"]);
+    let id = share["id"].to_string();
+    daemon.ctl(&["stop", &id]);
+    assert_eq!(daemon.ctl(&["list"]), serde_json::json!([]));
+    // its ticket no longer works
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let home = tempfile::tempdir().unwrap();
+    let output = sendme(["receive", share["ticket"].as_str().unwrap()], home.path())
+        .dir(tgt_dir.path())
+        .stderr_to_stdout()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[cfg(unix)]
+#[test]
+fn daemon_concurrent_shares() {
+    let daemon = Daemon::start();
+    let src_dir = tempfile::tempdir().unwrap();
+    let first = src_dir.path().join("first.txt");
+    let second = src_dir.path().join("second.txt");
+    std::fs::write(&first, "one").unwrap();
+    std::fs::write(&second, "two").unwrap();
+    let shares = [
+        daemon.ctl(&["add", first.to_str().unwrap()]),
+        daemon.ctl(&["add", second.to_str().unwrap()]),
+    ];
+    assert_ne!(shares[0]["id"], shares[1]["id"]);
+    assert_eq!(daemon.ctl(&["list"]).as_array().unwrap().len(), 2);
+    let tgt_dir = tempfile::tempdir().unwrap();
+    for share in &shares {
+        let ticket = BlobTicket::from_str(share["ticket"].as_str().unwrap()).unwrap();
+        receive(&ticket, tgt_dir.path());
+    }
+    assert_eq!(
+        std::fs::read_to_string(tgt_dir.path().join("first.txt")).unwrap(),
+        "one"
+    );
+    assert_eq!(
+        std::fs::read_to_string(tgt_dir.path().join("second.txt")).unwrap(),
+        "two"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn daemon_peer_progress() {
+    let daemon = Daemon::start();
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    std::fs::write(src_dir.path().join("a.txt"), "a".repeat(100_000)).unwrap();
+    std::fs::write(src_dir.path().join("b.txt"), "b".repeat(100_000)).unwrap();
+    let share = daemon.ctl(&["add", src_dir.path().to_str().unwrap()]);
+    let ticket = BlobTicket::from_str(share["ticket"].as_str().unwrap()).unwrap();
+    receive(&ticket, tgt_dir.path());
+    let id = share["id"].to_string();
+    let mut share = daemon.ctl(&["share", &id]);
+    // the daemon may not have seen the end of the transfer yet
+    for _ in 0..50 {
+        if share["downloads"] == 1 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        share = daemon.ctl(&["share", &id]);
+    }
+    let peers = share["peers"].as_array().unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0]["state"], "done");
+    assert!(peers[0]["bytes"].as_u64().unwrap() >= 200_000);
+    // the size probe and the download are one download
+    assert_eq!(share["downloads"], 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn daemon_cancel_import() {
+    use std::os::unix::net::UnixStream;
+
+    let daemon = Daemon::start();
+    let src_dir = tempfile::tempdir().unwrap();
+    for i in 0..1000 {
+        std::fs::write(src_dir.path().join(format!("{i}.txt")), i.to_string()).unwrap();
+    }
+    let mut stream = UnixStream::connect(&daemon.socket).unwrap();
+    let request = serde_json::json!({
+        "command": "add_share",
+        "paths": [src_dir.path()],
+    });
+    writeln!(stream, "{request}").unwrap();
+    // hanging up before the answer cancels the import
+    drop(stream);
+    // long enough for the import to finish, if it went on
+    for _ in 0..30 {
+        assert_eq!(daemon.ctl(&["list"]), serde_json::json!([]));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn send_recv_snapshot() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("draft.txt");
+    // large enough to be referenced rather than inlined into the store
+    let original = "first draft\n".repeat(10_000);
+    std::fs::write(&src_file, &original).unwrap();
+    let sender = send_ticket(
+        src_dir.path(),
+        &[src_file.to_str().unwrap(), "--import-mode", "snapshot"],
+    );
+    // edits after sharing don't reach the receiver
+    std::fs::write(&src_file, "second draft\n".repeat(10_000)).unwrap();
+    receive(&sender.ticket, tgt_dir.path());
+    let received = std::fs::read_to_string(tgt_dir.path().join("draft.txt")).unwrap();
+    assert_eq!(received, original);
+    sender.process.kill().unwrap();
+}
+
+#[test]
+fn send_recv_many_files() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let tree = src_dir.path().join("tree");
+    for i in 0..3000 {
+        let dir = tree
+            .join(format!("d{}", i % 7))
+            .join(format!("e{}", i % 11));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(format!("f{i}")), i.to_string()).unwrap();
+    }
+    let send = || {
+        // a fresh data directory each time, so everything is hashed
+        let data_dir = tempfile::tempdir().unwrap();
+        let data_arg = data_dir.path().to_str().unwrap();
+        let sender = send_ticket(
+            src_dir.path(),
+            &[tree.to_str().unwrap(), "--data-dir", data_arg],
+        );
+        (sender, data_dir)
+    };
+    // the hashing order doesn't change the collection
+    let (sender, _data_dir) = send();
+    let (other, _other_data_dir) = send();
+    other.process.kill().unwrap();
+    assert_eq!(sender.ticket.hash(), other.ticket.hash());
+    receive(&sender.ticket, tgt_dir.path());
+    let received = tgt_dir.path().join("tree").join("d3").join("e5");
+    assert_eq!(std::fs::read_to_string(received.join("f38")).unwrap(), "38");
+    sender.process.kill().unwrap();
+}
+
+#[test]
+fn send_recv_packed() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let tree = src_dir.path().join("tiny");
+    for i in 0..500 {
+        let dir = tree.join(format!("d{}", i % 5));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(format!("f{i}")), i.to_string()).unwrap();
+    }
+    // too big to be packed
+    let big = vec![3u8; 100_000];
+    std::fs::write(tree.join("big.bin"), &big).unwrap();
+    let sender = send_ticket(src_dir.path(), &[tree.to_str().unwrap(), "--pack"]);
+    receive(&sender.ticket, tgt_dir.path());
+    // the receiver unpacks into the same tree, without the packs
+    let received = tgt_dir.path().join("tiny");
+    for i in 0..500 {
+        let file = received.join(format!("d{}", i % 5)).join(format!("f{i}"));
+        assert_eq!(std::fs::read_to_string(file).unwrap(), i.to_string());
+    }
+    assert_eq!(std::fs::read(received.join("big.bin")).unwrap(), big);
+    assert_eq!(std::fs::read_dir(&received).unwrap().count(), 6);
+    assert!(!tgt_dir.path().join(".sendme-pack").exists());
+    assert!(!tgt_dir.path().join(".sendme-metadata.json").exists());
+    sender.process.kill().unwrap();
+}
+
+#[test]
+fn send_hash_cache() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let data_arg = data_dir.path().to_str().unwrap();
+    let src_file = src_dir.path().join("big.bin");
+    std::fs::write(&src_file, vec![7u8; 1_000_000]).unwrap();
+    let send = || {
+        let args = [src_file.to_str().unwrap(), "--data-dir", data_arg];
+        let sender = send_ticket(src_dir.path(), &args);
+        sender.process.kill().unwrap();
+        sender.ticket.hash()
+    };
+    let cache = |args: &[&str]| {
+        duct::cmd(
+            sendme_bin(),
+            ["cache", "--data-dir", data_arg].iter().chain(args),
+        )
+        .env_remove("RUST_LOG") // disable tracing
+        .read()
+        .unwrap()
+    };
+    let first = send();
+    assert!(
+        cache(&[]).contains("1 file(s), 976.56 KiB"),
+        "{}",
+        cache(&[])
+    );
+    // the second run finds the hash and makes the same collection
+    assert_eq!(send(), first);
+    std::fs::write(&src_file, vec![8u8; 1_000_000]).unwrap();
+    assert!(cache(&[]).contains("1 of them changed"));
+    assert_ne!(send(), first);
+    assert_eq!(cache(&["--clear"]), "cleared");
+    assert!(cache(&[]).contains("0 file(s)"));
+}
+
+#[test]
+fn gc_leftovers() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let cwd = tempfile::tempdir().unwrap();
+    let data_arg = data_dir.path().to_str().unwrap();
+    let leftover = data_dir.path().join("scratch").join("send-0123");
+    std::fs::create_dir_all(&leftover).unwrap();
+    std::fs::write(leftover.join("blobs.db"), vec![0u8; 1000]).unwrap();
+    let gc = |args: &[&str]| {
+        duct::cmd(
+            sendme_bin(),
+            ["gc", "--data-dir", data_arg].iter().chain(args),
+        )
+        .dir(cwd.path())
+        .env_remove("RUST_LOG") // disable tracing
+        .stderr_to_stdout()
+        .unchecked()
+        .read()
+        .unwrap()
+    };
+    let listed = gc(&[]);
+    assert!(listed.contains("send-0123"), "{listed}");
+    assert!(listed.contains("upload store, 1000 B"), "{listed}");
+    // only stores are deleted
+    let other = cwd.path().join("precious");
+    std::fs::create_dir(&other).unwrap();
+    assert!(gc(&[other.to_str().unwrap()]).contains("is not a sendme store"));
+    assert!(other.exists());
+    // a project that happens to have the name of a store
+    let project = cwd.path().join("send-email");
+    std::fs::create_dir(&project).unwrap();
+    std::fs::write(project.join("main.rs"), "fn main() {}").unwrap();
+    let refused = gc(&[project.to_str().unwrap()]);
+    assert!(refused.contains("is not a sendme store"), "{refused}");
+    assert!(project.join("main.rs").exists());
+    assert!(gc(&["--all"]).contains("deleted"));
+    assert!(!leftover.exists());
+    assert_eq!(gc(&[]), "nothing left behind");
+}
+
+#[test]
+fn identities() {
+    let config_dir = tempfile::tempdir().unwrap();
+    let sendme = |args: &[&str]| {
+        let output = duct::cmd(sendme_bin(), args)
+            .env("XDG_CONFIG_HOME", config_dir.path())
+            .env("HOME", config_dir.path())
+            .env_remove("IROH_SECRET")
+            .env_remove("RUST_LOG") // disable tracing
+            .stdout_capture()
+            .run()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    };
+    // the default identity is created once and kept
+    let default = sendme(&["identity", "show"]);
+    assert_eq!(sendme(&["identity", "show"]), default);
+    let secret = sendme(&["identity", "export"]);
+    let imported = duct::cmd(sendme_bin(), ["identity", "import", "copy"])
+        .env("XDG_CONFIG_HOME", config_dir.path())
+        .env("HOME", config_dir.path())
+        .env_remove("RUST_LOG") // disable tracing
+        .stdin_bytes(secret)
+        .read()
+        .unwrap();
+    assert_eq!(imported, default);
+    let work = sendme(&["identity", "new", "work"]);
+    assert_ne!(work, default);
+    sendme(&["identity", "use", "work"]);
+    assert_eq!(sendme(&["identity", "show"]), work);
+    let rotated = sendme(&["identity", "rotate"]);
+    assert_ne!(rotated, work);
+    assert_eq!(sendme(&["identity", "show"]), rotated);
+    let list = sendme(&["identity", "list"]);
+    assert!(list.contains(&format!("* work {rotated}")));
+    assert!(list.contains(&format!("  default {default}")));
+}
+
+#[test]
+fn send_stdin_recv_stdout() {
+    let home = tempfile::tempdir().unwrap();
+    let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let send_home = tempfile::tempdir().unwrap();
+    let mut send_cmd = sendme(["send", "-", "--name", "data.bin"], send_home.path())
+        .dir(src_dir.path())
+        .stdin_bytes(data.clone())
+        .stderr_to_stdout()
+        .reader()
+        .unwrap();
+    let output = read_ascii_lines(3, &mut send_cmd).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let ticket = output.split_ascii_whitespace().last().unwrap();
+    let ticket = BlobTicket::from_str(ticket).unwrap();
+    let receive_output = sendme(["receive", &ticket.to_string(), "--stdout"], home.path())
+        .dir(tgt_dir.path())
+        .stdout_capture()
+        .stderr_null()
+        .run()
+        .unwrap();
+    assert!(receive_output.status.success());
+    assert_eq!(receive_output.stdout, data);
+    // nothing is exported or stored when streaming
+    assert_eq!(std::fs::read_dir(tgt_dir.path()).unwrap().count(), 0);
+    // events would be mixed into the data
+    let json_output = sendme(
+        ["receive", &ticket.to_string(), "--stdout", "--json"],
+        home.path(),
+    )
+    .dir(tgt_dir.path())
+    .stderr_to_stdout()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert!(!json_output.status.success());
+    assert!(String::from_utf8_lossy(&json_output.stdout).contains("cannot be used with"));
+}
+
+#[test]
+fn send_recv_json_events() {
+    let home = tempfile::tempdir().unwrap();
+    let name = "somefile.bin";
+    let data = vec![2u8; 100];
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join(name);
+    std::fs::write(&src_file, &data).unwrap();
+    let send_home = tempfile::tempdir().unwrap();
+    let mut send_cmd = sendme(
+        ["send", src_file.as_os_str().to_str().unwrap(), "--json"],
+        send_home.path(),
+    )
+    .dir(src_dir.path())
+    .stderr_null()
+    .reader()
+    .unwrap();
+    // every line is an event, skip the import progress until the ticket shows up
+    let ticket = loop {
+        let line = read_ascii_lines(1, &mut send_cmd).unwrap();
+        assert!(!line.is_empty(), "send exited without a ticket");
+        let event: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        if event["event"] == "ticket_issued" {
+            break event["ticket"].as_str().unwrap().to_string();
+        }
+        assert_eq!(event["event"], "import_progress");
+        assert!(event["current"].is_string());
+        assert!(event["bytes_per_second"].is_u64());
+    };
+    let ticket = BlobTicket::from_str(&ticket).unwrap();
+    let receive_output = sendme(["receive", &ticket.to_string(), "--json"], home.path())
+        .dir(tgt_dir.path())
+        .stdout_capture()
+        .stderr_null()
+        .run()
+        .unwrap();
+    assert!(receive_output.status.success());
+    let events = String::from_utf8(receive_output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(events.first().unwrap()["event"], "connected");
+    let done = events.last().unwrap();
+    assert_eq!(done["event"], "done");
+    assert!(done["stats"]["bytes_read"].is_u64());
+    let tgt_data = std::fs::read(tgt_dir.path().join(name)).unwrap();
+    assert_eq!(tgt_data, data);
+}
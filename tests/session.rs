@@ -0,0 +1,91 @@
+//! The library API, as an app that embeds sendme uses it.
+use std::{path::Path, sync::Once};
+
+use sendme::backend::upload::UploadState;
+use sendme::interconnect::{CommonArgs, ExportTarget, TransferEvent};
+use sendme::session::{ReceiveSession, SendSession};
+
+/// Keep the identities of all tests in a directory of their own, away from the
+/// config directory of whoever runs the tests.
+fn isolate() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        let home = std::env::temp_dir().join(format!("sendme-session-{}", std::process::id()));
+        std::env::set_var("HOME", &home);
+        std::env::set_var("XDG_CONFIG_HOME", home.join("config"));
+        std::env::set_var("XDG_DATA_HOME", home.join("data"));
+        std::env::remove_var("IROH_SECRET");
+    });
+}
+
+/// Arguments for a transfer as the identity `name`, with its own data directory.
+fn common(name: &str, data_dir: &Path) -> CommonArgs {
+    CommonArgs {
+        identity: Some(name.to_string()),
+        data_dir: Some(data_dir.to_path_buf()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn session_send_receive() {
+    isolate();
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let send_data = tempfile::tempdir().unwrap();
+    let recv_data = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("notes.txt");
+    std::fs::write(&src_file, "sent from a library").unwrap();
+    let mut sender = SendSession::new(&src_file)
+        .common(common("session-sender", send_data.path()))
+        .spawn();
+    let events = sender.events();
+    let ticket = loop {
+        if let TransferEvent::TicketIssued { ticket, .. } = events.recv().await.unwrap() {
+            break ticket;
+        }
+    };
+    ReceiveSession::new(ticket)
+        .common(common("session-receiver", recv_data.path()))
+        .target(ExportTarget::Dir(tgt_dir.path().to_path_buf()))
+        .spawn()
+        .wait()
+        .await
+        .unwrap();
+    let received = std::fs::read_to_string(tgt_dir.path().join("notes.txt")).unwrap();
+    assert_eq!(received, "sent from a library");
+    // the sender sees the receiver finish
+    loop {
+        if let TransferEvent::PeerProgress(peer) = events.recv().await.unwrap() {
+            if peer.state == UploadState::Done {
+                assert!(peer.blobs > 0);
+                break;
+            }
+        }
+    }
+    sender.cancel();
+    let outcome = sender.wait().await.unwrap();
+    assert_eq!(outcome.files, 1);
+}
+
+#[tokio::test]
+async fn session_cancel_import() {
+    isolate();
+    let src_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    for i in 0..100 {
+        std::fs::write(src_dir.path().join(format!("{i}.txt")), i.to_string()).unwrap();
+    }
+    let mut sender = SendSession::new(src_dir.path())
+        .common(common("session-cancel", data_dir.path()))
+        .spawn();
+    // before the import can finish
+    sender.cancel();
+    let error = sender.wait().await.unwrap_err();
+    assert!(format!("{error:#}").contains("cancelled while importing"));
+    // nothing was issued
+    let events = sender.events();
+    while let Ok(event) = events.try_recv() {
+        assert!(!matches!(event, TransferEvent::TicketIssued { .. }));
+    }
+}